@@ -1,24 +1,89 @@
 extern crate parity_wasm;
 extern crate pwasm_utils as utils;
 use pwasm_utils::logger;
+extern crate clap;
 
-use std::env;
+use clap::{App, Arg};
+
+fn fail(msg: &str) -> ! {
+	eprintln!("{}", msg);
+	std::process::exit(1)
+}
 
 fn main() {
 	logger::init();
 
-	let args = env::args().collect::<Vec<_>>();
-	if args.len() != 3 {
-		println!("Usage: {} input_file.wasm output_file.wasm", args[0]);
-		return;
+	let matches = App::new("wasm-gas")
+		.arg(Arg::with_name("input")
+			.index(1)
+			.required(true)
+			.help("Input WASM file"))
+		.arg(Arg::with_name("output")
+			.index(2)
+			.required(true)
+			.help("Output WASM file"))
+		.arg(Arg::with_name("rules")
+			.long("rules")
+			.takes_value(true)
+			.value_name("file")
+			.help("File with the cost table of the instructions. Default: every instruction costs 1"))
+		.arg(Arg::with_name("module")
+			.long("module")
+			.takes_value(true)
+			.default_value("env")
+			.help("Module to import the gas function from"))
+		.arg(Arg::with_name("field")
+			.long("field")
+			.takes_value(true)
+			.default_value("gas")
+			.help("Name of the imported gas function"))
+		.arg(Arg::with_name("arg_type")
+			.long("arg-type")
+			.takes_value(true)
+			.possible_values(&["i32", "i64"])
+			.default_value("i32")
+			.help("Type of the gas amount passed to the gas function"))
+		.arg(Arg::with_name("extra_arg")
+			.long("extra-arg")
+			.takes_value(true)
+			.value_name("i32")
+			.help("Constant passed to the gas function as additional argument after the amount"))
+		.arg(Arg::with_name("reject_instrumented")
+			.long("reject-instrumented")
+			.help("Fail if the module already imports the gas function instead of reusing it"))
+		.get_matches();
+
+	let input = matches.value_of("input").expect("is required; qed");
+	let output = matches.value_of("output").expect("is required; qed");
+
+	let rules = match matches.value_of("rules") {
+		Some(path) => std::fs::read_to_string(path)
+			.unwrap_or_else(|e| fail(&format!("Failed to read {}: {}", path, e)))
+			.parse()
+			.unwrap_or_else(|e| fail(&format!("Invalid rules in {}: {}", path, e))),
+		None => utils::rules::Set::default(),
+	};
+
+	let mut config = utils::GasInjectionConfig::new(matches.value_of("module").expect("has default; qed"))
+		.with_field(matches.value_of("field").expect("has default; qed"));
+	if matches.value_of("arg_type") == Some("i64") {
+		config = config.with_argument_type(utils::GasArgument::I64);
+	}
+	if let Some(extra_arg) = matches.value_of("extra_arg") {
+		config = config.with_extra_argument(
+			extra_arg.parse().unwrap_or_else(|_| fail("--extra-arg must be an i32"))
+		);
+	}
+	if matches.is_present("reject_instrumented") {
+		config = config.with_existing_import(utils::gas::ExistingImport::Reject);
 	}
 
 	// Loading module
-	let module = parity_wasm::deserialize_file(&args[1]).expect("Module deserialization to succeed");
+	let module = parity_wasm::deserialize_file(input).expect("Module deserialization to succeed");
 
-	let result = utils::inject_gas_counter(
-		module, &utils::rules::Set::default(), "env"
-	).expect("Failed to inject gas. Some forbidden opcodes?");
+	let result = utils::inject_gas_counter_with_backend(
+		module, &rules, utils::GasBackend::ImportedFunction(&config)
+	).map_err(|(_, error)| error).expect("Failed to inject gas");
 
-	parity_wasm::serialize_to_file(&args[2], result).expect("Module serialization to succeed")
+	parity_wasm::serialize_to_file(output, result).expect("Module serialization to succeed")
 }
@@ -1,28 +1,185 @@
 extern crate pwasm_utils as utils;
 extern crate parity_wasm;
 use pwasm_utils::logger;
+extern crate clap;
 
-use std::env;
-use utils::stack_height;
+use clap::{App, Arg};
+use utils::stack_height::{self, IndirectCalls, LimiterConfig, StackCostModel, StackHeightGlobal, ValueSizes};
+
+fn fail(msg: &str) -> ! {
+	eprintln!("{}", msg);
+	std::process::exit(1)
+}
 
 fn main() {
 	logger::init();
 
-	let args = env::args().collect::<Vec<_>>();
-	if args.len() != 3 {
-		println!("Usage: {} input_file.wasm output_file.wasm", args[0]);
+	let matches = App::new("wasm-stack-height")
+		.arg(Arg::with_name("input")
+			.index(1)
+			.required(true)
+			.help("Input WASM file"))
+		.arg(Arg::with_name("output")
+			.index(2)
+			.required_unless_one(&["dry_run", "report"])
+			.help("Output WASM file"))
+		.arg(Arg::with_name("limit")
+			.long("limit")
+			.takes_value(true)
+			.value_name("cost")
+			.conflicts_with("stack_bytes")
+			.help("Highest stack height, in units of the cost model. Default: 1024"))
+		.arg(Arg::with_name("stack_bytes")
+			.long("stack-bytes")
+			.takes_value(true)
+			.value_name("bytes")
+			.help("Compute the limit from a stack size in bytes, divided by the size of a value"))
+		.arg(Arg::with_name("value_size")
+			.long("value-size")
+			.takes_value(true)
+			.value_name("bytes")
+			.default_value("8")
+			.help("Size of a value in bytes used by --stack-bytes. Ignored by the bytes cost model, \
+				which measures in bytes already"))
+		.arg(Arg::with_name("cost_model")
+			.long("cost-model")
+			.takes_value(true)
+			.possible_values(&["legacy", "uniform", "bytes"])
+			.default_value("legacy")
+			.help("How stack costs are computed: legacy counts local declarations, uniform counts \
+				every local and parameter, bytes additionally weights values by their size"))
+		.arg(Arg::with_name("export_global")
+			.long("export-global")
+			.takes_value(true)
+			.value_name("name")
+			.conflicts_with("import_global")
+			.help("Export the global tracking the stack height under this name"))
+		.arg(Arg::with_name("import_global")
+			.long("import-global")
+			.takes_value(true)
+			.number_of_values(2)
+			.value_names(&["module", "field"])
+			.help("Import the global tracking the stack height instead of adding it"))
+		.arg(Arg::with_name("skip_bounded")
+			.long("skip-bounded")
+			.help("Leave the code untouched if the stack height provably never exceeds the limit"))
+		.arg(Arg::with_name("elide_bounded_calls")
+			.long("elide-bounded-calls")
+			.help("Don't instrument calls to functions which can't recurse, charging their worst-case \
+				stack depth to the caller instead"))
+		.arg(Arg::with_name("indirect_calls")
+			.long("indirect-calls")
+			.takes_value(true)
+			.possible_values(&["thunks", "lookup"])
+			.default_value("thunks")
+			.help("How indirect calls are charged: thunks wraps every table entry, lookup charges at the \
+				call site using a single generated function if the table can't change at runtime"))
+		.arg(Arg::with_name("dry_run")
+			.long("dry-run")
+			.conflicts_with("report")
+			.help("Print the stack cost of every function instead of writing the output"))
+		.arg(Arg::with_name("report")
+			.long("report")
+			.help("Print the stack costs, calls and worst-case stack depths instead of writing the output"))
+		.get_matches();
+
+	let input = matches.value_of("input").expect("is required; qed");
+
+	let model = match matches.value_of("cost_model") {
+		Some("uniform") => StackCostModel::Sized(ValueSizes::uniform()),
+		Some("bytes") => StackCostModel::Sized(ValueSizes::bytes()),
+		_ => StackCostModel::LocalDeclarations,
+	};
+
+	let limit = match (matches.value_of("limit"), matches.value_of("stack_bytes")) {
+		(Some(limit), _) => limit.parse().unwrap_or_else(|_| fail("--limit must be a u32")),
+		(None, Some(bytes)) => {
+			let bytes: u32 = bytes.parse().unwrap_or_else(|_| fail("--stack-bytes must be a u32"));
+			let value_size: u32 = match model {
+				StackCostModel::Sized(sizes) if sizes == ValueSizes::bytes() => 1,
+				_ => matches.value_of("value_size").expect("has default; qed")
+					.parse()
+					.unwrap_or_else(|_| fail("--value-size must be a u32")),
+			};
+			if value_size == 0 {
+				fail("--value-size must not be zero");
+			}
+			bytes / value_size
+		},
+		(None, None) => 1024,
+	};
+
+	// Loading module
+	let module = parity_wasm::deserialize_file(input).expect("Module deserialization to succeed");
+
+	if matches.is_present("report") {
+		print_report(&module, model);
 		return;
 	}
 
-	let input_file = &args[1];
-	let output_file = &args[2];
+	if matches.is_present("dry_run") {
+		let costs = stack_height::compute_stack_costs(&module, model)
+			.expect("Failed to compute stack costs");
+		println!("{:>8} {:>10}", "function", "cost");
+		for (func_idx, cost) in costs.iter().enumerate() {
+			println!("{:>8} {:>10}", func_idx, cost);
+		}
+		println!("limit: {}", limit);
+		return;
+	}
 
-	// Loading module
-	let module = parity_wasm::deserialize_file(&input_file).expect("Module deserialization to succeed");
+	let global = match (matches.value_of("export_global"), matches.values_of("import_global")) {
+		(Some(name), _) => StackHeightGlobal::Exported(name),
+		(None, Some(mut values)) => StackHeightGlobal::Imported {
+			module: values.next().expect("takes two values; qed"),
+			field: values.next().expect("takes two values; qed"),
+		},
+		(None, None) => StackHeightGlobal::Internal,
+	};
+
+	let config = LimiterConfig::new(limit)
+		.with_cost_model(model)
+		.with_global(global)
+		.with_skip_bounded(matches.is_present("skip_bounded"))
+		.with_elide_bounded_calls(matches.is_present("elide_bounded_calls"))
+		.with_indirect_calls(match matches.value_of("indirect_calls") {
+			Some("lookup") => IndirectCalls::CostLookup,
+			_ => IndirectCalls::Thunks,
+		});
+	let result = stack_height::inject_limiter_with_config(module, &config)
+		.expect("Failed to inject stack height counter");
+
+	let output = matches.value_of("output").expect("is required unless printing; qed");
+	parity_wasm::serialize_to_file(output, result).expect("Module serialization to succeed")
+}
+
+fn print_report(module: &parity_wasm::elements::Module, model: StackCostModel) {
+	let analysis = stack_height::analyze(module, model).expect("Failed to analyze stack height");
+	let format_depth = |depth: Option<u64>| match depth {
+		Some(depth) => depth.to_string(),
+		None => "unbounded".to_string(),
+	};
+	let format_indices = |indices: &[u32]| {
+		indices.iter().map(|idx| idx.to_string()).collect::<Vec<_>>().join(", ")
+	};
 
-	let result = stack_height::inject_limiter(
-		module, 1024
-	).expect("Failed to inject stack height counter");
+	println!("{:>8} {:>10} {:>10}  callees", "function", "cost", "max depth");
+	for function in analysis.functions() {
+		let mut callees = format_indices(function.callees());
+		if !function.indirect_callees().is_empty() {
+			callees = format!("{} (indirect: {})", callees, format_indices(function.indirect_callees()));
+		}
+		println!(
+			"{:>8} {:>10} {:>10}  {}",
+			function.func_idx(),
+			function.stack_cost(),
+			format_depth(function.max_depth()),
+			callees.trim_start(),
+		);
+	}
 
-	parity_wasm::serialize_to_file(&output_file, result).expect("Module serialization to succeed")
+	for cycle in analysis.cycles() {
+		println!("recursive cycle: {}", format_indices(cycle));
+	}
+	println!("max depth: {}", format_depth(analysis.max_depth()));
 }
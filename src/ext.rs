@@ -6,22 +6,10 @@ use parity_wasm::{elements, builder};
 use byteorder::{LittleEndian, ByteOrder};
 
 use crate::optimizer::{import_section, export_section};
+use crate::remap::{IndexMapping, IndexRemap};
 
 type Insertion = (usize, u32, u32, String);
 
-pub fn update_call_index(instructions: &mut elements::Instructions, original_imports: usize, inserts: &[Insertion]) {
-	use parity_wasm::elements::Instruction::*;
-	for instruction in instructions.elements_mut().iter_mut() {
-		if let Call(call_index) = instruction {
-			if let Some(pos) = inserts.iter().position(|x| x.1 == *call_index) {
-				*call_index = (original_imports + pos) as u32;
-			} else if *call_index as usize > original_imports {
-				*call_index += inserts.len() as u32;
-			}
-		}
-	}
-}
-
 pub fn memory_section(module: &mut elements::Module) -> Option<&mut elements::MemorySection> {
 	for section in module.sections_mut() {
 	   if let elements::Section::Memory(sect) = section {
@@ -99,12 +87,9 @@ pub fn shrink_unknown_stack(
 		match section {
 			elements::Section::Data(data_section) => {
 				for data_segment in data_section.entries_mut() {
-					if *data_segment
-						.offset()
-						.as_ref()
-						.expect("parity-wasm is compiled without bulk-memory operations")
-						.code() == [elements::Instruction::I32Const(4), elements::Instruction::End]
-					{
+					// Passive segments have no offset and therefore can't hold the stack pointer.
+					let offset = data_segment.offset().as_ref().map(|offset| offset.code());
+					if offset == Some(&[elements::Instruction::I32Const(4), elements::Instruction::End][..]) {
 						assert_eq!(data_segment.value().len(), 4);
 						let current_val = LittleEndian::read_u32(data_segment.value());
 						let new_val = current_val - shrink_amount;
@@ -172,33 +157,69 @@ pub fn externalize(
 	// Back to mutable access
 	let mut module = mbuilder.build();
 
-	// Third, rewire all calls to imported functions and update all other calls indices
-	for section in module.sections_mut() {
-		match section {
-			elements::Section::Code(code_section) => {
-				for func_body in code_section.bodies_mut() {
-					update_call_index(func_body.code_mut(), import_funcs_total, &replaces);
-				}
-			},
-			elements::Section::Export(export_section) => {
-				for export in export_section.entries_mut() {
-					if let elements::Internal::Function(func_index) = export.internal_mut() {
-						if *func_index >= import_funcs_total as u32 { *func_index += replaces.len() as u32; }
-					}
-				}
-			},
-			elements::Section::Element(elements_section) => {
-				for segment in elements_section.entries_mut() {
-					// update all indirect call addresses initial values
-					for func_index in segment.members_mut() {
-						if *func_index >= import_funcs_total as u32 { *func_index += replaces.len() as u32; }
-					}
-				}
-			},
-			_ => { }
+	// Third, update all references to the defined functions, which are shifted by the new imports
+	let import_funcs_total = import_funcs_total as u32;
+	let inserted = replaces.len() as u32;
+	IndexRemap::new()
+		.with_functions(IndexMapping::Inserted { index: import_funcs_total, count: inserted })
+		.apply(&mut module);
+
+	// Fourth, rewire all calls of the replaced functions to the new imports
+	let mut rewired: Vec<u32> = (0..module.functions_space() as u32).collect();
+	for (pos, (_, func_idx, _, _)) in replaces.iter().enumerate() {
+		rewired[(func_idx + inserted) as usize] = import_funcs_total + pos as u32;
+	}
+	let rewire = IndexRemap::new().with_functions(IndexMapping::Explicit(rewired));
+	if let Some(code_section) = module.code_section_mut() {
+		for func_body in code_section.bodies_mut() {
+			rewire.apply_instructions(func_body.code_mut().elements_mut());
 		}
 	}
 
 	module
 
 }
+
+#[cfg(test)]
+mod tests {
+	extern crate wabt;
+
+	use parity_wasm::elements;
+	use super::*;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	#[test]
+	fn externalize_updates_all_references() {
+		let module = parse_wat(r#"
+(module
+	(import "env" "f" (func $f))
+	(func $first
+		call $replaced
+	)
+	(func $replaced
+		call $first
+	)
+	(table 2 anyfunc)
+	(elem (i32.const 0) $first $replaced)
+	(export "replaced" (func $replaced))
+	(start $first)
+)
+"#);
+
+		let module = externalize(module, vec!["replaced"]);
+
+		use parity_wasm::elements::Instruction::*;
+		let imports = module.import_section().unwrap().entries();
+		assert_eq!(imports[1].field(), "replaced");
+		let bodies = module.code_section().unwrap().bodies();
+		assert_eq!(bodies[0].code().elements(), &[Call(1), End]);
+		assert_eq!(bodies[1].code().elements(), &[Call(2), End]);
+		assert_eq!(module.elements_section().unwrap().entries()[0].members(), &[2, 3]);
+		assert_eq!(*module.export_section().unwrap().entries()[0].internal(), elements::Internal::Function(3));
+		assert_eq!(module.start_section(), Some(2));
+	}
+}
@@ -0,0 +1,288 @@
+//! This module is used to report the gas costs of a module without instrumenting it.
+//!
+//! The costs are derived from the same metered blocks that `inject_gas_counter` uses, so the
+//! reported numbers are exactly what the injected metering code would charge, except for the
+//! instructions replaced by helpers.
+
+use crate::std::vec::Vec;
+
+use parity_wasm::elements;
+use crate::rules::Rules;
+use super::{determine_metered_blocks, import_call_costs, Error, HelperCosts, MeteredBlock};
+
+/// Statically known gas costs of a function defined in the module.
+///
+/// Costs of the called functions and the costs of the helpers charging for `memory.grow` and bulk
+/// instructions are not included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionCosts {
+	func_idx: u32,
+	blocks: Vec<MeteredBlock>,
+	max_path_cost: u64,
+}
+
+impl FunctionCosts {
+	/// Index of the function in the function index space.
+	pub fn func_idx(&self) -> u32 {
+		self.func_idx
+	}
+
+	/// Metered blocks of the function body ordered by their start position.
+	pub fn blocks(&self) -> &[MeteredBlock] {
+		&self.blocks
+	}
+
+	/// Number of places in the function body at which gas would be charged.
+	pub fn charge_points(&self) -> usize {
+		self.blocks.len()
+	}
+
+	/// Sum of the costs of all metered blocks, i.e. the cost of executing every instruction of
+	/// the function body exactly once.
+	pub fn total_cost(&self) -> u64 {
+		self.blocks.iter().fold(0, |total, block| total.saturating_add(block.cost()))
+	}
+
+	/// The highest cost charged along any acyclic path through the function body.
+	///
+	/// An acyclic path never takes a branch back to the beginning of a loop. It ends with either
+	/// leaving the function, a trap or a branch to a loop.
+	pub fn max_path_cost(&self) -> u64 {
+		self.max_path_cost
+	}
+}
+
+/// Computes the gas costs of all functions defined in the module according to `rules`.
+///
+/// The module is not modified. The function fails under the same conditions as
+/// `inject_gas_counter` does.
+pub fn function_costs<R: Rules>(
+	module: &elements::Module,
+	rules: &R,
+) -> Result<Vec<FunctionCosts>, Error> {
+	let func_imports = module.import_count(elements::ImportCountType::Function) as u32;
+	let bodies = module.code_section().map(|cs| cs.bodies()).unwrap_or(&[]);
+	let call_costs = import_call_costs(module, rules);
+
+	bodies
+		.iter()
+		.enumerate()
+		.map(|(i, func_body)| {
+			let func_idx = func_imports + i as u32;
+			let instructions = func_body.code();
+			let blocks = determine_metered_blocks(
+				instructions,
+				rules,
+				&call_costs,
+				&HelperCosts::default(),
+				u64::MAX,
+			)
+				.map_err(|failure| failure.into_error(func_idx))?;
+			let max_path_cost = compute_max_path_cost(instructions.elements(), &blocks);
+			Ok(FunctionCosts { func_idx, blocks, max_path_cost })
+		})
+		.collect()
+}
+
+/// Control frame used for finding the most expensive acyclic path.
+///
+/// The costs are `None` if there is no path reaching the respective point.
+struct Frame {
+	/// Branches to a loop jump backwards and therefore end an acyclic path.
+	is_loop: bool,
+	/// The cost at the `if` instruction which is where the path not taking the `then` branch
+	/// continues from. This is taken once an `else` is encountered.
+	alternative: Option<u64>,
+	/// The highest cost of all paths leaving the frame by a branch to its end.
+	exit: Option<u64>,
+}
+
+/// Finds the highest cost charged along any acyclic path through `instructions`.
+///
+/// This expects the control stack to be validated by `determine_metered_blocks`.
+pub(super) fn compute_max_path_cost(instructions: &[elements::Instruction], blocks: &[MeteredBlock]) -> u64 {
+	use parity_wasm::elements::Instruction::*;
+
+	let mut frames = vec![Frame { is_loop: false, alternative: None, exit: None }];
+	let mut max_path_cost = None;
+	let mut cost = Some(0u64);
+	let mut block_iter = blocks.iter().peekable();
+
+	for (cursor, instruction) in instructions.iter().enumerate() {
+		if let Some(block) = block_iter.peek() {
+			if block.start_pos == cursor {
+				cost = cost.map(|cost| cost.saturating_add(block.cost));
+				block_iter.next();
+			}
+		}
+
+		// Record the current path as complete if it branches to `label` and extend the exit of
+		// the target block otherwise.
+		let mut branch = |frames: &mut Vec<Frame>, label: u32, cost: Option<u64>| {
+			let target_idx = frames.len() - 1 - label as usize;
+			let target = &mut frames[target_idx];
+			if target_idx == 0 || target.is_loop {
+				max_path_cost = max_path_cost.max(cost);
+			} else {
+				target.exit = target.exit.max(cost);
+			}
+		};
+
+		match instruction {
+			Block(_) | Loop(_) => {
+				frames.push(Frame {
+					is_loop: matches!(instruction, Loop(_)),
+					alternative: None,
+					exit: None,
+				});
+			}
+			If(_) => {
+				frames.push(Frame { is_loop: false, alternative: cost, exit: None });
+			}
+			Else => {
+				let frame = frames.last_mut()
+					.expect("`else` is always preceded by `if`; the control stack is validated; qed");
+				frame.exit = frame.exit.max(cost);
+				cost = frame.alternative.take();
+			}
+			End => {
+				let frame = frames.pop()
+					.expect("each `end` closes a frame; the control stack is validated; qed");
+				cost = cost.max(frame.alternative).max(frame.exit);
+				if frames.is_empty() {
+					max_path_cost = max_path_cost.max(cost);
+					break;
+				}
+			}
+			Br(label) => {
+				branch(&mut frames, *label, cost);
+				cost = None;
+			}
+			BrIf(label) => {
+				branch(&mut frames, *label, cost);
+			}
+			BrTable(br_table_data) => {
+				for label in br_table_data.table.iter().chain(Some(&br_table_data.default)) {
+					branch(&mut frames, *label, cost);
+				}
+				cost = None;
+			}
+			Return | Unreachable => {
+				max_path_cost = max_path_cost.max(cost);
+				cost = None;
+			}
+			_ => {}
+		}
+	}
+
+	max_path_cost.unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate wabt;
+
+	use parity_wasm::elements;
+	use super::*;
+	use crate::rules;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	fn costs_of(source: &str) -> FunctionCosts {
+		function_costs(&parse_wat(source), &rules::Set::default())
+			.expect("Failed to compute costs")
+			.pop()
+			.expect("Module must define a function")
+	}
+
+	#[test]
+	fn straight_line() {
+		let costs = costs_of(r#"
+(module
+	(func (result i32)
+		(i32.add (i32.const 1) (i32.const 2))))
+"#);
+
+		assert_eq!(costs.func_idx(), 0);
+		assert_eq!(costs.charge_points(), 1);
+		assert_eq!(costs.blocks()[0].start_pos(), 0);
+		assert_eq!(costs.total_cost(), 3);
+		assert_eq!(costs.max_path_cost(), 3);
+	}
+
+	#[test]
+	fn if_else_takes_more_expensive_branch() {
+		let costs = costs_of(r#"
+(module
+	(import "env" "f" (func))
+	(func (param i32) (result i32)
+		(get_local 0)
+		(if (result i32)
+			(then
+				(i32.const 1))
+			(else
+				(i32.const 1)
+				(i32.const 2)
+				(i32.add)))))
+"#);
+
+		assert_eq!(costs.func_idx(), 1);
+		assert_eq!(costs.charge_points(), 3);
+		assert_eq!(costs.total_cost(), 6);
+		// get_local, if, and the else branch.
+		assert_eq!(costs.max_path_cost(), 5);
+	}
+
+	#[test]
+	fn loop_body_is_counted_once() {
+		let costs = costs_of(r#"
+(module
+	(func (param i32)
+		(loop
+			(get_local 0)
+			(i32.const 1)
+			(i32.sub)
+			(tee_local 0)
+			(br_if 0))
+		(get_local 0)
+		(drop)))
+"#);
+
+		assert_eq!(costs.total_cost(), 8);
+		assert_eq!(costs.max_path_cost(), 8);
+	}
+
+	#[test]
+	fn early_return() {
+		let costs = costs_of(r#"
+(module
+	(func (param i32)
+		(block
+			(br_if 0 (get_local 0))
+			(return))
+		(nop)
+		(nop)
+		(nop)))
+"#);
+
+		assert_eq!(costs.charge_points(), 3);
+		assert_eq!(costs.total_cost(), 7);
+		// Skipping the `return` passes the three `nop`s.
+		assert_eq!(costs.max_path_cost(), 6);
+	}
+
+	#[test]
+	fn forbidden_instruction() {
+		let module = parse_wat(r#"
+(module
+	(func (result f32)
+		(f32.const 1)))
+"#);
+
+		let rules = rules::Set::default().with_forbidden_floats();
+		assert!(function_costs(&module, &rules).is_err());
+	}
+}
@@ -0,0 +1,106 @@
+//! This module is used to charge the size dependent costs of bulk memory and table operations.
+
+use crate::std::vec::Vec;
+
+use parity_wasm::elements::{self, BulkInstruction, Instruction, ValueType};
+use crate::rules::Rules;
+use super::{add_metered_helper, metered_helper_cost, GasMeter};
+
+/// Adds every bulk instruction of `instructions` which has a cost per byte or table element to
+/// `metered`, unless it is listed already.
+pub(super) fn collect_metered<R: Rules>(
+	instructions: &elements::Instructions,
+	rules: &R,
+	metered: &mut Vec<BulkInstruction>,
+) {
+	for instruction in instructions.elements() {
+		if let Instruction::Bulk(bulk) = instruction {
+			if is_sized(bulk) && rules.bulk_cost(bulk).is_some() && !metered.contains(bulk) {
+				metered.push(bulk.clone());
+			}
+		}
+	}
+}
+
+/// Replaces the `metered` bulk instructions by calls to helpers charging for their size.
+///
+/// One helper per instruction is added in the order of `metered`, the first one getting the
+/// function index `first_helper`.
+pub(super) fn add_bulk_counters<R: Rules>(
+	mut module: elements::Module,
+	rules: &R,
+	gas_meter: GasMeter,
+	metered: Vec<BulkInstruction>,
+	first_helper: u32,
+) -> elements::Module {
+	use parity_wasm::elements::Instruction::*;
+
+	if let Some(code_section) = module.code_section_mut() {
+		for func_body in code_section.bodies_mut() {
+			for instruction in func_body.code_mut().elements_mut() {
+				let helper = match instruction {
+					Bulk(bulk) => metered.iter().position(|metered| metered == bulk),
+					_ => None,
+				};
+				if let Some(helper) = helper {
+					*instruction = Call(first_helper + helper as u32);
+				}
+			}
+		}
+	}
+
+	for bulk in metered {
+		module = add_metered_helper(
+			module,
+			gas_meter,
+			vec![ValueType::I32; 3],
+			None,
+			helper_charge(rules, &bulk),
+			&[GetLocal(0), GetLocal(1), GetLocal(2), Bulk(bulk)],
+		);
+	}
+
+	module
+}
+
+/// Returns the static cost of the helper charging for the metered bulk `instruction` as
+/// computed by `metered_helper_cost`.
+pub(super) fn helper_cost<R: Rules>(
+	rules: &R,
+	gas_meter: GasMeter,
+	instruction: &BulkInstruction,
+) -> u64 {
+	use parity_wasm::elements::Instruction::*;
+
+	metered_helper_cost(
+		rules,
+		gas_meter,
+		3,
+		helper_charge(rules, instruction),
+		&[GetLocal(0), GetLocal(1), GetLocal(2), Bulk(instruction.clone())],
+	)
+}
+
+/// Returns the instructions computing the charge of the metered bulk `instruction`.
+fn helper_charge<R: Rules>(rules: &R, instruction: &BulkInstruction) -> Vec<Instruction> {
+	use parity_wasm::elements::Instruction::*;
+
+	let cost = rules.bulk_cost(instruction).expect("only instructions with a cost are collected; qed");
+	// All sized instructions take the number of bytes or elements as their last operand.
+	vec![
+		GetLocal(2),
+		I64ExtendUI32,
+		I64Const(cost.get() as i64),
+		I64Mul,
+	]
+}
+
+/// Whether `instruction` operates on a number of bytes or table elements given at runtime.
+pub(super) fn is_sized(instruction: &BulkInstruction) -> bool {
+	use parity_wasm::elements::BulkInstruction::*;
+
+	match instruction {
+		MemoryInit(_) | MemoryCopy | MemoryFill | TableInit(_) | TableCopy => true,
+		MemoryDrop(_) | TableDrop(_) => false,
+	}
+}
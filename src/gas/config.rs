@@ -0,0 +1,135 @@
+//! Configuration of the imported function which charges the gas.
+
+use crate::std::string::{String, ToString};
+
+use parity_wasm::elements::ValueType;
+
+/// What to do if the module already imports the gas function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExistingImport {
+	/// Use the existing import to charge gas. No function indices are shifted.
+	///
+	/// A module which already calls the import the way the instrumentation does, i.e. with
+	/// constant amounts or from helpers charging for `memory.grow` and bulk operations, is
+	/// considered to be instrumented and left unchanged. Otherwise the import is taken over even
+	/// if the module imports it for another purpose, and the existing calls are kept as they are.
+	Reuse,
+	/// Fail with `Error::AlreadyInstrumented`, leaving the module untouched.
+	Reject,
+}
+
+/// Type of the gas amount passed to the imported gas function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GasArgument {
+	/// Amounts are passed as i32 and never exceed `i32::MAX`.
+	I32,
+	/// Amounts are passed as i64, to be interpreted as unsigned, and may exceed `i32::MAX`.
+	I64,
+}
+
+impl GasArgument {
+	pub(crate) fn value_type(self) -> ValueType {
+		match self {
+			GasArgument::I32 => ValueType::I32,
+			GasArgument::I64 => ValueType::I64,
+		}
+	}
+
+	/// The highest amount which can be passed.
+	pub(crate) fn max_amount(self) -> u64 {
+		match self {
+			GasArgument::I32 => i32::MAX as u64,
+			GasArgument::I64 => u64::MAX,
+		}
+	}
+}
+
+/// Describes the function which the instrumented module imports to charge gas.
+///
+/// By default the function is "gas" imported from the module passed to `new` and has the type
+/// signature [i32] -> []. The first argument is always the amount of gas to charge.
+///
+/// An import is considered to be the gas function if its module, field and signature match the
+/// config. By default such an import is reused instead of adding another one, see
+/// `ExistingImport::Reuse`.
+///
+/// ```
+/// use pwasm_utils::{GasArgument, GasInjectionConfig};
+///
+/// // env.charge_gas(amount: i64, category: i32)
+/// let config = GasInjectionConfig::new("env")
+///     .with_field("charge_gas")
+///     .with_argument_type(GasArgument::I64)
+///     .with_extra_argument(2);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GasInjectionConfig {
+	module: String,
+	field: String,
+	argument_type: GasArgument,
+	extra_argument: Option<i32>,
+	existing_import: ExistingImport,
+}
+
+impl GasInjectionConfig {
+	pub fn new(module: &str) -> Self {
+		GasInjectionConfig {
+			module: module.to_string(),
+			field: "gas".to_string(),
+			argument_type: GasArgument::I32,
+			extra_argument: None,
+			existing_import: ExistingImport::Reuse,
+		}
+	}
+
+	/// Sets the name of the imported function.
+	pub fn with_field(mut self, field: &str) -> Self {
+		self.field = field.to_string();
+		self
+	}
+
+	/// Sets the type of the gas amount argument.
+	pub fn with_argument_type(mut self, argument_type: GasArgument) -> Self {
+		self.argument_type = argument_type;
+		self
+	}
+
+	/// Passes `value` as an additional i32 argument following the gas amount to every call.
+	pub fn with_extra_argument(mut self, value: i32) -> Self {
+		self.extra_argument = Some(value);
+		self
+	}
+
+	/// Sets what to do if the module already imports the gas function.
+	pub fn with_existing_import(mut self, existing_import: ExistingImport) -> Self {
+		self.existing_import = existing_import;
+		self
+	}
+
+	pub fn module(&self) -> &str {
+		&self.module
+	}
+
+	pub fn field(&self) -> &str {
+		&self.field
+	}
+
+	pub fn argument_type(&self) -> GasArgument {
+		self.argument_type
+	}
+
+	pub fn extra_argument(&self) -> Option<i32> {
+		self.extra_argument
+	}
+
+	pub fn existing_import(&self) -> ExistingImport {
+		self.existing_import
+	}
+
+	/// Parameter types of the imported function.
+	pub(crate) fn params(&self) -> impl Iterator<Item = ValueType> {
+		Some(self.argument_type.value_type())
+			.into_iter()
+			.chain(self.extra_argument.map(|_| ValueType::I32))
+	}
+}
@@ -3,26 +3,188 @@
 //! The primary public interface is the `inject_gas_counter` function which transforms a given
 //! module into one that charges gas for code to be executed. See function documentation for usage
 //! and details.
-
+//!
+//! `inject_gas_counter_with_backend` allows to choose how the gas is charged. See `Backend` for
+//! the available options.
+//!
+//! `function_costs` reports the statically known costs of every function without modifying the
+//! module and `strip_gas_counter` removes the metering code again.
+
+mod analysis;
+#[cfg(feature = "bulk")]
+mod bulk;
+mod config;
+mod strip;
 #[cfg(test)]
 mod validation;
 
 use crate::std::cmp::min;
+use crate::std::fmt;
 use crate::std::mem;
+use crate::std::num::NonZeroU32;
 use crate::std::vec::Vec;
 
 use parity_wasm::{elements, elements::ValueType, builder};
-use crate::rules::Rules;
+use crate::rules::{MemoryGrowCost, Rules};
+use crate::remap::{IndexMapping, IndexRemap};
+
+pub use self::analysis::{function_costs, FunctionCosts};
+pub use self::config::{ExistingImport, GasArgument, GasInjectionConfig};
+pub use self::strip::{strip_gas_counter, StripError};
+use self::strip::charges_gas;
+
+/// The reason the gas metering instrumentation of a function failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+	/// The instruction is forbidden by the rule set.
+	ForbiddenInstruction,
+	/// The summed up cost of a metered block overflowed.
+	CostOverflow,
+	/// The control flow of the function is malformed, e.g. a branch targets a non-existent
+	/// label or an `end` doesn't close any block.
+	MalformedControlStack,
+}
 
-pub fn update_call_index(instructions: &mut elements::Instructions, inserted_index: u32) {
-	use parity_wasm::elements::Instruction::*;
-	for instruction in instructions.elements_mut().iter_mut() {
-		if let Call(call_index) = instruction {
-			if *call_index >= inserted_index { *call_index += 1}
+/// Gas metering instrumentation error.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+	/// A function body couldn't be instrumented.
+	Function {
+		/// Index of the function in the function index space of the original module.
+		func_idx: u32,
+		/// Position of the offending instruction in the function body.
+		offset: usize,
+		/// The offending instruction.
+		instruction: elements::Instruction,
+		/// What exactly went wrong.
+		kind: ErrorKind,
+	},
+	/// The module already imports the gas function and the config rejects existing imports.
+	AlreadyInstrumented {
+		/// Index of the imported gas function.
+		func_idx: u32,
+	},
+}
+
+impl fmt::Display for ErrorKind {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match *self {
+			ErrorKind::ForbiddenInstruction => write!(f, "Instruction is forbidden by the rule set"),
+			ErrorKind::CostOverflow => write!(f, "Cost of the metered block overflowed"),
+			ErrorKind::MalformedControlStack => write!(f, "Control stack is malformed"),
+		}
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			Error::Function { func_idx, offset, instruction, kind } => write!(
+				f,
+				"{} in function {} at instruction {} ({:?})",
+				kind, func_idx, offset, instruction,
+			),
+			Error::AlreadyInstrumented { func_idx } => write!(
+				f,
+				"Module already imports the gas function as function {}",
+				func_idx,
+			),
+		}
+	}
+}
+
+/// Selects the way an instrumented module is charged for the gas it consumes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend<'a> {
+	/// Import the function described by the config and call it at the beginning of every metered
+	/// block.
+	///
+	/// `inject_gas_counter` uses `GasInjectionConfig::new` with the passed module name.
+	ImportedFunction(&'a GasInjectionConfig),
+	/// Keep the remaining gas in a mutable i64 global exported under the specified name.
+	///
+	/// Every metered block subtracts its cost from the global inline and executes `unreachable`
+	/// if the remaining gas is less than the cost. The value of the global is interpreted as
+	/// unsigned and it is left untouched when execution traps because of exhausted gas. The
+	/// embedder is expected to set the global before executing any code and to read it back
+	/// afterwards. No import is added, so no function indices are shifted.
+	MutableGlobal(&'a str),
+}
+
+/// The place the injected metering code charges gas to.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum GasMeter {
+	/// The imported gas function as described by `GasInjectionConfig`.
+	Function {
+		gas_func: u32,
+		argument_type: GasArgument,
+		extra_argument: Option<i32>,
+	},
+	/// Global index of the mutable global holding the remaining gas.
+	Global(u32),
+}
+
+impl GasMeter {
+	/// Number of instructions `charge` emits.
+	fn charge_len(&self) -> usize {
+		match self {
+			GasMeter::Function { extra_argument, .. } => 2 + extra_argument.is_some() as usize,
+			GasMeter::Global(_) => 10,
+		}
+	}
+
+	/// Appends instructions which charge the static `cost`, which must not exceed the highest
+	/// amount of the gas argument.
+	fn charge(&self, instructions: &mut Vec<elements::Instruction>, cost: u64) {
+		use parity_wasm::elements::Instruction::*;
+		match *self {
+			GasMeter::Function { argument_type: GasArgument::I32, .. } => {
+				instructions.push(I32Const(cost as i32));
+				self.call(instructions);
+			}
+			GasMeter::Function { .. } => {
+				instructions.push(I64Const(cost as i64));
+				self.call(instructions);
+			}
+			GasMeter::Global(gas_global) => {
+				instructions.extend_from_slice(&[
+					// if gas_left < cost: unreachable
+					GetGlobal(gas_global),
+					I64Const(cost as i64),
+					I64LtU,
+					If(elements::BlockType::NoResult),
+					Unreachable,
+					End,
+					// gas_left -= cost
+					GetGlobal(gas_global),
+					I64Const(cost as i64),
+					I64Sub,
+					SetGlobal(gas_global),
+				]);
+			}
+		}
+	}
+
+	/// Appends the call to the gas function which expects the amount on the stack.
+	fn call(&self, instructions: &mut Vec<elements::Instruction>) {
+		use parity_wasm::elements::Instruction::*;
+		if let GasMeter::Function { gas_func, extra_argument, .. } = *self {
+			if let Some(value) = extra_argument {
+				instructions.push(I32Const(value));
+			}
+			instructions.push(Call(gas_func));
 		}
 	}
 }
 
+/// Shifts the calls to functions with an index greater or equal to `inserted_index` by one.
+#[deprecated(note = "Use `IndexRemap`, which updates all references to functions")]
+pub fn update_call_index(instructions: &mut elements::Instructions, inserted_index: u32) {
+	IndexRemap::new()
+		.with_functions(IndexMapping::Inserted { index: inserted_index, count: 1 })
+		.apply_instructions(instructions.elements_mut());
+}
+
 /// A control flow block is opened with the `block`, `loop`, and `if` instructions and is closed
 /// with `end`. Each block implicitly defines a new label. The control blocks form a stack during
 /// program execution.
@@ -64,12 +226,24 @@ struct ControlBlock {
 /// A block of code that metering instructions will be inserted at the beginning of. Metered blocks
 /// are constructed with the property that, in the absence of any traps, either all instructions in
 /// the block are executed or none are.
-#[derive(Debug)]
-pub(crate) struct MeteredBlock {
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MeteredBlock {
 	/// Index of the first instruction (aka `Opcode`) in the block.
 	start_pos: usize,
 	/// Sum of costs of all instructions until end of the block.
-	cost: u32,
+	cost: u64,
+}
+
+impl MeteredBlock {
+	/// Index of the first instruction in the block. The charge is injected right before it.
+	pub fn start_pos(&self) -> usize {
+		self.start_pos
+	}
+
+	/// Sum of costs of all instructions until end of the block.
+	pub fn cost(&self) -> u64 {
+		self.cost
+	}
 }
 
 /// Counter is used to manage state during the gas metering algorithm implemented by
@@ -84,16 +258,27 @@ struct Counter {
 
 	/// A list of metered blocks that have been finalized, meaning they will no longer change.
 	finalized_blocks: Vec<MeteredBlock>,
+
+	/// The highest cost a metered block may have.
+	max_cost: u64,
 }
 
 impl Counter {
-	fn new() -> Counter {
+	fn new(max_cost: u64) -> Counter {
 		Counter {
 			stack: Vec::new(),
 			finalized_blocks: Vec::new(),
+			max_cost,
 		}
 	}
 
+	/// Sum of the costs `a` and `b`, which must not exceed `max_cost`.
+	fn add_costs(&self, a: u64, b: u64) -> Result<u64, ErrorKind> {
+		a.checked_add(b)
+			.filter(|cost| *cost <= self.max_cost)
+			.ok_or(ErrorKind::CostOverflow)
+	}
+
 	/// Open a new control block. The cursor is the position of the first instruction in the block.
 	fn begin_control_block(&mut self, cursor: usize, is_loop: bool) {
 		let index = self.stack.len();
@@ -109,13 +294,13 @@ impl Counter {
 
 	/// Close the last control block. The cursor is the position of the final (pseudo-)instruction
 	/// in the block.
-	fn finalize_control_block(&mut self, cursor: usize) -> Result<(), ()> {
+	fn finalize_control_block(&mut self, cursor: usize) -> Result<(), ErrorKind> {
 		// This either finalizes the active metered block or merges its cost into the active
 		// metered block in the previous control block on the stack.
 		self.finalize_metered_block(cursor)?;
 
 		// Pop the control block stack.
-		let closing_control_block = self.stack.pop().ok_or_else(|| ())?;
+		let closing_control_block = self.stack.pop().ok_or(ErrorKind::MalformedControlStack)?;
 		let closing_control_index = self.stack.len();
 
 		if self.stack.is_empty() {
@@ -124,7 +309,7 @@ impl Counter {
 
 		// Update the lowest_forward_br_target for the control block now on top of the stack.
 		{
-			let control_block = self.stack.last_mut().ok_or_else(|| ())?;
+			let control_block = self.stack.last_mut().ok_or(ErrorKind::MalformedControlStack)?;
 			control_block.lowest_forward_br_target = min(
 				control_block.lowest_forward_br_target,
 				closing_control_block.lowest_forward_br_target
@@ -144,9 +329,9 @@ impl Counter {
 	/// Finalize the current active metered block.
 	///
 	/// Finalized blocks have final cost which will not change later.
-	fn finalize_metered_block(&mut self, cursor: usize) -> Result<(), ()> {
+	fn finalize_metered_block(&mut self, cursor: usize) -> Result<(), ErrorKind> {
 		let closing_metered_block = {
-			let control_block = self.stack.last_mut().ok_or_else(|| ())?;
+			let control_block = self.stack.last_mut().ok_or(ErrorKind::MalformedControlStack)?;
 			mem::replace(
 				&mut control_block.active_metered_block,
 				MeteredBlock {
@@ -163,11 +348,12 @@ impl Counter {
 		// cost into the other active metered block to avoid injecting unnecessary instructions.
 		let last_index = self.stack.len() - 1;
 		if last_index > 0 {
-			let prev_control_block = self.stack.get_mut(last_index - 1)
-				.expect("last_index is greater than 0; last_index is stack size - 1; qed");
-			let prev_metered_block = &mut prev_control_block.active_metered_block;
+			let prev_metered_block = &self.stack.get(last_index - 1)
+				.expect("last_index is greater than 0; last_index is stack size - 1; qed")
+				.active_metered_block;
 			if closing_metered_block.start_pos == prev_metered_block.start_pos {
-				prev_metered_block.cost += closing_metered_block.cost;
+				let cost = self.add_costs(prev_metered_block.cost, closing_metered_block.cost)?;
+				self.stack[last_index - 1].active_metered_block.cost = cost;
 				return Ok(())
 			}
 		}
@@ -182,20 +368,20 @@ impl Counter {
 	/// instruction in the program. The indices are the stack positions of the target control
 	/// blocks. Recall that the index is 0 for a `return` and relatively indexed from the top of
 	/// the stack by the label of `br`, `br_if`, and `br_table` instructions.
-	fn branch(&mut self, cursor: usize, indices: &[usize]) -> Result<(), ()> {
+	fn branch(&mut self, cursor: usize, indices: &[usize]) -> Result<(), ErrorKind> {
 		self.finalize_metered_block(cursor)?;
 
 		// Update the lowest_forward_br_target of the current control block.
 		for &index in indices {
 			let target_is_loop = {
-				let target_block = self.stack.get(index).ok_or_else(|| ())?;
+				let target_block = self.stack.get(index).ok_or(ErrorKind::MalformedControlStack)?;
 				target_block.is_loop
 			};
 			if target_is_loop {
 				continue;
 			}
 
-			let control_block = self.stack.last_mut().ok_or_else(|| ())?;
+			let control_block = self.stack.last_mut().ok_or(ErrorKind::MalformedControlStack)?;
 			control_block.lowest_forward_br_target =
 				min(control_block.lowest_forward_br_target, index);
 		}
@@ -209,15 +395,15 @@ impl Counter {
 	}
 
 	/// Get a reference to the currently active metered block.
-	fn active_metered_block(&mut self) -> Result<&mut MeteredBlock, ()> {
-		let top_block = self.stack.last_mut().ok_or_else(|| ())?;
+	fn active_metered_block(&mut self) -> Result<&mut MeteredBlock, ErrorKind> {
+		let top_block = self.stack.last_mut().ok_or(ErrorKind::MalformedControlStack)?;
 		Ok(&mut top_block.active_metered_block)
 	}
 
 	/// Increment the cost of the current block by the specified value.
-	fn increment(&mut self, val: u32) -> Result<(), ()> {
-		let top_block = self.active_metered_block()?;
-		top_block.cost = top_block.cost.checked_add(val).ok_or_else(|| ())?;
+	fn increment(&mut self, val: u64) -> Result<(), ErrorKind> {
+		let cost = self.active_metered_block()?.cost;
+		self.active_metered_block()?.cost = self.add_costs(cost, val)?;
 		Ok(())
 	}
 }
@@ -234,34 +420,123 @@ fn inject_grow_counter(instructions: &mut elements::Instructions, grow_counter_f
 	counter
 }
 
+/// The maximum number of pages a 32 bit memory can have.
+const MAX_PAGES: u32 = 65536;
+
+/// Appends instructions which leave the i64 amount of gas to charge for growing the memory by
+/// the number of pages in local 0 on the stack.
+fn push_grow_charge(instructions: &mut Vec<elements::Instruction>, cost: &MemoryGrowCost) {
+	use parity_wasm::elements::Instruction::*;
+
+	// The product of two u32 values always fits into u64.
+	let per_page = |instructions: &mut Vec<elements::Instruction>, cost: NonZeroU32| {
+		instructions.extend_from_slice(&[
+			GetLocal(0),
+			I64ExtendUI32,
+			I64Const(cost.get() as i64),
+			I64Mul,
+		]);
+	};
+
+	match cost {
+		MemoryGrowCost::Linear(cost) => per_page(instructions, *cost),
+		MemoryGrowCost::Tiered(tiers) => {
+			let (last, tiers) = match tiers.split_last() {
+				Some(split) => split,
+				None => return instructions.push(I64Const(0)),
+			};
+			// if pages <= max_pages { pages * cost } else { <next tier> }
+			for (max_pages, cost) in tiers {
+				instructions.extend_from_slice(&[
+					GetLocal(0),
+					I32Const(*max_pages as i32),
+					I32LeU,
+					If(elements::BlockType::Value(ValueType::I64)),
+				]);
+				per_page(instructions, *cost);
+				instructions.push(Else);
+			}
+			per_page(instructions, last.1);
+			instructions.extend(tiers.iter().map(|_| End));
+		}
+		MemoryGrowCost::TotalSize(cost) => {
+			// Requests beyond the maximum memory size always fail, so they are charged as if they
+			// requested the maximum. This keeps the size within 17 bits and the product within u64.
+			instructions.extend_from_slice(&[
+				CurrentMemory(0),
+				I64ExtendUI32,
+				// min(pages, MAX_PAGES)
+				GetLocal(0),
+				I32Const(MAX_PAGES as i32),
+				GetLocal(0),
+				I32Const(MAX_PAGES as i32),
+				I32LtU,
+				Select,
+				I64ExtendUI32,
+				I64Add,
+				I64Const(cost.get() as i64),
+				I64Mul,
+			]);
+		}
+		MemoryGrowCost::Capped { max_pages, cost } => {
+			// if pages > max_pages: unreachable
+			instructions.extend_from_slice(&[
+				GetLocal(0),
+				I32Const(*max_pages as i32),
+				I32GtU,
+				If(elements::BlockType::NoResult),
+				Unreachable,
+				End,
+			]);
+			push_grow_charge(instructions, cost);
+		}
+	}
+}
+
 fn add_grow_counter<R: Rules>(
 	module: elements::Module,
 	rules: &R,
-	gas_func: u32
+	gas_meter: GasMeter,
 ) -> elements::Module {
 	use parity_wasm::elements::Instruction::*;
-	use crate::rules::MemoryGrowCost;
 
 	let cost = match rules.memory_grow_cost() {
 		None => return module,
-		Some(MemoryGrowCost::Linear(val)) => val.get(),
+		Some(cost) => cost,
 	};
 
+	let mut instructions = Vec::new();
+	push_grow_charge(&mut instructions, &cost);
+
+	add_metered_helper(
+		module,
+		gas_meter,
+		vec![ValueType::I32],
+		Some(ValueType::I32),
+		instructions,
+		&[GetLocal(0), GrowMemory(0)],
+	)
+}
+
+/// Adds a function which charges the i64 amount computed by `charge` and then executes
+/// `instructions` on its parameters.
+fn add_metered_helper(
+	module: elements::Module,
+	gas_meter: GasMeter,
+	params: Vec<ValueType>,
+	result: Option<ValueType>,
+	charge: Vec<elements::Instruction>,
+	instructions: &[elements::Instruction],
+) -> elements::Module {
+	let (locals, body) = metered_helper_body(gas_meter, params.len() as u32, charge, instructions);
+
 	let mut b = builder::from_module(module);
 	b.push_function(
 		builder::function()
-			.signature().with_param(ValueType::I32).with_result(ValueType::I32).build()
+			.signature().with_params(params).with_results(result.into_iter().collect()).build()
 			.body()
-				.with_instructions(elements::Instructions::new(vec![
-					GetLocal(0),
-					GetLocal(0),
-					I32Const(cost as i32),
-					I32Mul,
-					// todo: there should be strong guarantee that it does not return anything on stack?
-					Call(gas_func),
-					GrowMemory(0),
-					End,
-				]))
+				.with_locals(locals)
+				.with_instructions(elements::Instructions::new(body))
 				.build()
 			.build()
 	);
@@ -269,102 +544,333 @@ fn add_grow_counter<R: Rules>(
 	b.build()
 }
 
-pub(crate) fn determine_metered_blocks<R: Rules>(
-	instructions: &elements::Instructions,
-	rules: &R,
-) -> Result<Vec<MeteredBlock>, ()> {
+/// Returns the locals and the instructions of the helper added by `add_metered_helper` for a
+/// function with `params` parameters.
+///
+/// The local following the parameters is used as scratch space.
+fn metered_helper_body(
+	gas_meter: GasMeter,
+	params: u32,
+	mut charge: Vec<elements::Instruction>,
+	instructions: &[elements::Instruction],
+) -> (Vec<elements::Local>, Vec<elements::Instruction>) {
 	use parity_wasm::elements::Instruction::*;
 
-	let mut counter = Counter::new();
+	let scratch = params;
+	let locals = match gas_meter {
+		// The charge is computed without overflows in i64 but must be passed as i32. Charges which
+		// don't fit are unpayable and trap instead of silently wrapping around.
+		GasMeter::Function { argument_type: GasArgument::I32, .. } => {
+			charge.extend_from_slice(&[
+				// if charge > i32::MAX: unreachable
+				TeeLocal(scratch),
+				I64Const(i32::MAX as i64),
+				I64GtU,
+				If(elements::BlockType::NoResult),
+				Unreachable,
+				End,
+				GetLocal(scratch),
+				I32WrapI64,
+			]);
+			gas_meter.call(&mut charge);
+			vec![elements::Local::new(1, ValueType::I64)]
+		}
+		GasMeter::Function { .. } => {
+			gas_meter.call(&mut charge);
+			Vec::new()
+		}
+		GasMeter::Global(gas_global) => {
+			charge.extend_from_slice(&[
+				// if charge > gas_left: unreachable
+				TeeLocal(scratch),
+				GetGlobal(gas_global),
+				I64GtU,
+				If(elements::BlockType::NoResult),
+				Unreachable,
+				End,
+				// gas_left -= charge
+				GetGlobal(gas_global),
+				GetLocal(scratch),
+				I64Sub,
+				SetGlobal(gas_global),
+			]);
+			vec![elements::Local::new(1, ValueType::I64)]
+		}
+	};
+	charge.extend_from_slice(instructions);
+	charge.push(End);
 
-	// Begin an implicit function (i.e. `func...end`) block.
-	counter.begin_control_block(0, false);
+	(locals, charge)
+}
 
-	for cursor in 0..instructions.elements().len() {
-		let instruction = &instructions.elements()[cursor];
-		let instruction_cost = rules.instruction_cost(instruction).ok_or(())?;
-		match instruction {
-			Block(_) => {
-				counter.increment(instruction_cost)?;
-
-				// Begin new block. The cost of the following opcodes until `end` or `else` will
-				// be included into this block. The start position is set to that of the previous
-				// active metered block to signal that they should be merged in order to reduce
-				// unnecessary metering instructions.
-				let top_block_start_pos = counter.active_metered_block()?.start_pos;
-				counter.begin_control_block(top_block_start_pos, false);
-			}
-			If(_) => {
-				counter.increment(instruction_cost)?;
-				counter.begin_control_block(cursor + 1, false);
-			}
-			Loop(_) => {
-				counter.increment(instruction_cost)?;
-				counter.begin_control_block(cursor + 1, true);
-			}
-			End => {
-				counter.finalize_control_block(cursor)?;
-			},
-			Else => {
-				counter.finalize_metered_block(cursor)?;
-			}
-			Br(label) | BrIf(label) => {
-				counter.increment(instruction_cost)?;
+/// Returns the static cost of the helper added by `add_metered_helper` besides the cost of the
+/// last of `instructions`, which is the one the helper replaces.
+///
+/// This is the cost of the most expensive path through the helper. Instructions the rules forbid
+/// are free, as the helper is generated code rather than part of the module.
+fn metered_helper_cost<R: Rules>(
+	rules: &R,
+	gas_meter: GasMeter,
+	params: u32,
+	charge: Vec<elements::Instruction>,
+	instructions: &[elements::Instruction],
+) -> u64 {
+	struct HelperRules<'a, R>(&'a R);
+
+	impl<R: Rules> Rules for HelperRules<'_, R> {
+		fn instruction_cost(&self, instruction: &elements::Instruction) -> Option<u64> {
+			Some(self.0.instruction_cost(instruction).unwrap_or(0))
+		}
 
-				// Label is a relative index into the control stack.
-				let active_index = counter.active_control_block_index().ok_or_else(|| ())?;
-				let target_index = active_index.checked_sub(*label as usize).ok_or_else(|| ())?;
-				counter.branch(cursor, &[target_index])?;
-			}
-			BrTable(br_table_data) => {
-				counter.increment(instruction_cost)?;
-
-				let active_index = counter.active_control_block_index().ok_or_else(|| ())?;
-				let target_indices = [br_table_data.default]
-					.iter()
-					.chain(br_table_data.table.iter())
-					.map(|label| active_index.checked_sub(*label as usize))
-					.collect::<Option<Vec<_>>>()
-					.ok_or_else(|| ())?;
-				counter.branch(cursor, &target_indices)?;
-			}
-			Return => {
-				counter.increment(instruction_cost)?;
-				counter.branch(cursor, &[0])?;
-			}
-			_ => {
-				// An ordinal non control flow instruction increments the cost of the current block.
-				counter.increment(instruction_cost)?;
+		fn memory_grow_cost(&self) -> Option<MemoryGrowCost> {
+			None
+		}
+	}
+
+	let rules = HelperRules(rules);
+	let (_, body) = metered_helper_body(gas_meter, params, charge, instructions);
+	let body = elements::Instructions::new(body);
+	let path_cost = determine_metered_blocks(&body, &rules, &[], &HelperCosts::default(), u64::MAX)
+		.map(|blocks| analysis::compute_max_path_cost(body.elements(), &blocks))
+		.unwrap_or(u64::MAX);
+	let replaced_cost = instructions
+		.last()
+		.and_then(|instruction| rules.instruction_cost(instruction))
+		.unwrap_or(0);
+	path_cost.saturating_sub(replaced_cost)
+}
+
+/// Static costs of the helpers replacing the instructions with a dynamic cost.
+///
+/// The helpers aren't metered themselves, so the cost of the instructions they execute besides
+/// the replaced one is charged along with the replaced instruction.
+#[derive(Debug, Default)]
+pub(crate) struct HelperCosts {
+	grow: u64,
+	#[cfg(feature = "bulk")]
+	bulk: Vec<(elements::BulkInstruction, u64)>,
+}
+
+impl HelperCosts {
+	/// Computes the costs of the helpers that charging gas to `gas_meter` adds to `module`.
+	fn new<R: Rules>(module: &elements::Module, rules: &R, gas_meter: GasMeter) -> Self {
+		use parity_wasm::elements::Instruction::*;
+
+		let grow = rules
+			.memory_grow_cost()
+			.map(|cost| {
+				let mut charge = Vec::new();
+				push_grow_charge(&mut charge, &cost);
+				metered_helper_cost(rules, gas_meter, 1, charge, &[GetLocal(0), GrowMemory(0)])
+			})
+			.unwrap_or(0);
+
+		#[cfg(feature = "bulk")]
+		let bulk = {
+			let mut metered = Vec::new();
+			for func_body in module.code_section().map(|cs| cs.bodies()).unwrap_or(&[]) {
+				bulk::collect_metered(func_body.code(), rules, &mut metered);
 			}
+			metered
+				.into_iter()
+				.map(|bulk| {
+					let cost = bulk::helper_cost(rules, gas_meter, &bulk);
+					(bulk, cost)
+				})
+				.collect()
+		};
+		#[cfg(not(feature = "bulk"))]
+		let _ = module;
+
+		HelperCosts {
+			grow,
+			#[cfg(feature = "bulk")]
+			bulk,
+		}
+	}
+
+	/// Returns the cost of the helper replacing `instruction`, or zero if there is none.
+	fn cost(&self, instruction: &elements::Instruction) -> u64 {
+		match instruction {
+			elements::Instruction::GrowMemory(_) => self.grow,
+			#[cfg(feature = "bulk")]
+			elements::Instruction::Bulk(bulk) => self
+				.bulk
+				.iter()
+				.find(|(metered, _)| metered == bulk)
+				.map_or(0, |(_, cost)| *cost),
+			_ => 0,
+		}
+	}
+
+	/// The bulk instructions replaced by helpers in the order of the helpers.
+	#[cfg(feature = "bulk")]
+	fn metered_bulk(&self) -> Vec<elements::BulkInstruction> {
+		self.bulk.iter().map(|(bulk, _)| bulk.clone()).collect()
+	}
+}
+
+/// A failure at a specific instruction of a function body.
+#[derive(Debug)]
+pub(crate) struct Failure {
+	offset: usize,
+	instruction: elements::Instruction,
+	kind: ErrorKind,
+}
+
+impl Failure {
+	fn into_error(self, func_idx: u32) -> Error {
+		Error::Function {
+			func_idx,
+			offset: self.offset,
+			instruction: self.instruction,
+			kind: self.kind,
 		}
 	}
+}
+
+/// Returns the additional cost of calling each imported function, indexed by function index.
+pub(crate) fn import_call_costs<R: Rules>(module: &elements::Module, rules: &R) -> Vec<u64> {
+	module.import_section()
+		.map(|import_section| import_section.entries())
+		.unwrap_or(&[])
+		.iter()
+		.filter(|entry| matches!(entry.external(), elements::External::Function(_)))
+		.map(|entry| rules.import_call_cost(entry.module(), entry.field()))
+		.collect()
+}
+
+/// Determines the metered blocks of a function body.
+///
+/// `call_costs` holds the additional costs of calling the imported functions as returned by
+/// `import_call_costs` and `helper_costs` the ones of the instructions replaced by helpers.
+/// Metered blocks costing more than `max_cost` fail with `ErrorKind::CostOverflow` at the
+/// instruction exceeding it.
+pub(crate) fn determine_metered_blocks<R: Rules>(
+	instructions: &elements::Instructions,
+	rules: &R,
+	call_costs: &[u64],
+	helper_costs: &HelperCosts,
+	max_cost: u64,
+) -> Result<Vec<MeteredBlock>, Failure> {
+	let mut counter = Counter::new(max_cost);
+
+	// Begin an implicit function (i.e. `func...end`) block.
+	counter.begin_control_block(0, false);
+
+	for (cursor, instruction) in instructions.elements().iter().enumerate() {
+		meter_instruction(&mut counter, cursor, instruction, rules, call_costs, helper_costs)
+			.map_err(|kind| Failure { offset: cursor, instruction: instruction.clone(), kind })?;
+	}
 
 	counter.finalized_blocks.sort_unstable_by_key(|block| block.start_pos);
+
+	// A block starting past the last instruction can't be charged anywhere, blame the function
+	// end for it.
+	let len = instructions.elements().len();
+	if matches!(counter.finalized_blocks.last(), Some(block) if block.start_pos >= len) {
+		return Err(Failure {
+			offset: len.saturating_sub(1),
+			instruction: instructions.elements().last().cloned().unwrap_or(elements::Instruction::End),
+			kind: ErrorKind::MalformedControlStack,
+		});
+	}
+
 	Ok(counter.finalized_blocks)
 }
 
-pub fn inject_counter<R: Rules>(
-	instructions: &mut elements::Instructions,
+/// Account for the instruction at position `cursor` in the metered blocks of `counter`.
+fn meter_instruction<R: Rules>(
+	counter: &mut Counter,
+	cursor: usize,
+	instruction: &elements::Instruction,
 	rules: &R,
-	gas_func: u32,
-) -> Result<(), ()> {
-	let blocks = determine_metered_blocks(instructions, rules)?;
-	insert_metering_calls(instructions, blocks, gas_func)
+	call_costs: &[u64],
+	helper_costs: &HelperCosts,
+) -> Result<(), ErrorKind> {
+	use parity_wasm::elements::Instruction::*;
+
+	let mut instruction_cost = rules.instruction_cost(instruction)
+		.ok_or(ErrorKind::ForbiddenInstruction)?
+		.checked_add(helper_costs.cost(instruction))
+		.ok_or(ErrorKind::CostOverflow)?;
+	if let Call(func_idx) = instruction {
+		if let Some(call_cost) = call_costs.get(*func_idx as usize) {
+			instruction_cost = instruction_cost.checked_add(*call_cost)
+				.ok_or(ErrorKind::CostOverflow)?;
+		}
+	}
+	match instruction {
+		Block(_) => {
+			counter.increment(instruction_cost)?;
+
+			// Begin new block. The cost of the following opcodes until `end` or `else` will
+			// be included into this block. The start position is set to that of the previous
+			// active metered block to signal that they should be merged in order to reduce
+			// unnecessary metering instructions.
+			let top_block_start_pos = counter.active_metered_block()?.start_pos;
+			counter.begin_control_block(top_block_start_pos, false);
+		}
+		If(_) => {
+			counter.increment(instruction_cost)?;
+			counter.begin_control_block(cursor + 1, false);
+		}
+		Loop(_) => {
+			counter.increment(instruction_cost)?;
+			counter.begin_control_block(cursor + 1, true);
+		}
+		End => {
+			counter.finalize_control_block(cursor)?;
+		},
+		Else => {
+			counter.finalize_metered_block(cursor)?;
+		}
+		Br(label) | BrIf(label) => {
+			counter.increment(instruction_cost)?;
+
+			// Label is a relative index into the control stack.
+			let active_index = counter.active_control_block_index()
+				.ok_or(ErrorKind::MalformedControlStack)?;
+			let target_index = active_index.checked_sub(*label as usize)
+				.ok_or(ErrorKind::MalformedControlStack)?;
+			counter.branch(cursor, &[target_index])?;
+		}
+		BrTable(br_table_data) => {
+			counter.increment(instruction_cost)?;
+
+			let active_index = counter.active_control_block_index()
+				.ok_or(ErrorKind::MalformedControlStack)?;
+			let target_indices = [br_table_data.default]
+				.iter()
+				.chain(br_table_data.table.iter())
+				.map(|label| active_index.checked_sub(*label as usize))
+				.collect::<Option<Vec<_>>>()
+				.ok_or(ErrorKind::MalformedControlStack)?;
+			counter.branch(cursor, &target_indices)?;
+		}
+		Return => {
+			counter.increment(instruction_cost)?;
+			counter.branch(cursor, &[0])?;
+		}
+		_ => {
+			// An ordinal non control flow instruction increments the cost of the current block.
+			counter.increment(instruction_cost)?;
+		}
+	}
+
+	Ok(())
 }
 
-// Then insert metering calls into a sequence of instructions given the block locations and costs.
+// Then insert metering calls into a sequence of instructions given the block locations and costs
+// as determined by `determine_metered_blocks`.
 fn insert_metering_calls(
 	instructions: &mut elements::Instructions,
 	blocks: Vec<MeteredBlock>,
-	gas_func: u32,
-)
-	-> Result<(), ()>
-{
-	use parity_wasm::elements::Instruction::*;
-
+	gas_meter: GasMeter,
+) {
 	// To do this in linear time, construct a new vector of instructions, copying over old
 	// instructions one by one and injecting new ones as required.
-	let new_instrs_len = instructions.elements().len() + 2 * blocks.len();
+	let new_instrs_len = instructions.elements().len() + gas_meter.charge_len() * blocks.len();
 	let original_instrs = mem::replace(
 		instructions.elements_mut(), Vec::with_capacity(new_instrs_len)
 	);
@@ -375,8 +881,7 @@ fn insert_metering_calls(
 		// If there the next block starts at this position, inject metering instructions.
 		let used_block = if let Some(block) = block_iter.peek() {
 			if block.start_pos == original_pos {
-				new_instrs.push(I32Const(block.cost as i32));
-				new_instrs.push(Call(gas_func));
+				gas_meter.charge(new_instrs, block.cost);
 				true
 			} else { false }
 		} else { false };
@@ -388,12 +893,6 @@ fn insert_metering_calls(
 		// Copy over the original instruction.
 		new_instrs.push(instr);
 	}
-
-	if block_iter.next().is_some() {
-		return Err(());
-	}
-
-	Ok(())
 }
 
 /// Transforms a given module into one that charges gas for code to be executed by proxy of an
@@ -417,38 +916,194 @@ fn insert_metering_calls(
 /// modules instrumented with this metering code may charge gas for instructions not executed in
 /// the event of a trap.
 ///
+/// Calls to imported functions are charged the `Rules::import_call_cost` of the import in
+/// addition to the cost of the `call` instruction as part of the enclosing metered block.
+///
 /// Additionally, each `memory.grow` instruction found in the module is instrumented to first make
 /// a call to charge gas for the additional pages requested. This cannot be done as part of the
 /// block level gas charges as the gas cost is not static and depends on the stack argument to
-/// `memory.grow`.
+/// `memory.grow`. The charge is computed in 64 bits and the helper traps if it exceeds
+/// `i32::MAX`, so that an overflowing charge never reaches the "gas" function. In the same way,
+/// bulk memory and table operations with a `Rules::bulk_cost` are replaced by helpers charging for
+/// the number of bytes or elements they operate on. The helpers contain no metering code of their
+/// own. Instead, the cost of the most expensive path through a helper is charged along with the
+/// instruction it replaces.
 ///
 /// The above transformations are performed for every function body defined in the module. This
 /// function also rewrites all function indices references by code, table elements, etc., since
-/// the addition of an imported functions changes the indices of module-defined functions.
+/// the addition of an imported functions changes the indices of module-defined functions. A "gas"
+/// function the module already imports with the expected signature is reused instead, in which
+/// case no indices change. If the module already charges gas by calling it the way this function
+/// does, it is returned unchanged, so instrumenting a module twice is the same as doing it once.
+/// See `ExistingImport` for rejecting such modules instead.
 ///
 /// This routine runs in time linear in the size of the input module.
 ///
-/// The function fails if the module contains any operation forbidden by gas rule set, returning
-/// the original module as an Err.
+/// The function fails if the module contains any operation forbidden by gas rule set or a function
+/// body can't be instrumented for another reason, returning the unmodified module along with the
+/// `Error` describing the offending instruction. This includes metered blocks costing more than
+/// `i32::MAX`, which can be charged by passing the amount as `GasArgument::I64` instead.
 pub fn inject_gas_counter<R: Rules>(
 	module: elements::Module,
 	rules: &R,
 	gas_module_name: &str,
 )
-	-> Result<elements::Module, elements::Module>
+	-> Result<elements::Module, (elements::Module, Error)>
+{
+	inject_gas_counter_with_backend(
+		module,
+		rules,
+		Backend::ImportedFunction(&GasInjectionConfig::new(gas_module_name)),
+	)
+}
+
+/// Transforms a given module into one that charges gas for code to be executed using the
+/// specified `backend`.
+///
+/// The placement of the metering code is the same for all backends and is described in the
+/// documentation of `inject_gas_counter`. Only the code which performs the charge differs.
+///
+/// The function fails under the same conditions as `inject_gas_counter`.
+pub fn inject_gas_counter_with_backend<R: Rules>(
+	module: elements::Module,
+	rules: &R,
+	backend: Backend,
+)
+	-> Result<elements::Module, (elements::Module, Error)>
 {
+	let func_imports = module.import_count(elements::ImportCountType::Function) as u32;
+	// The gas import is added after all other imports, so their indices stay the same.
+	let call_costs = import_call_costs(&module, rules);
+	let gas_import = match backend {
+		Backend::ImportedFunction(config) => find_gas_import(&module, config),
+		Backend::MutableGlobal(_) => None,
+	};
+	let max_charge = match backend {
+		Backend::ImportedFunction(config) => config.argument_type().max_amount(),
+		Backend::MutableGlobal(_) => u64::MAX,
+	};
+	match (backend, gas_import) {
+		(Backend::ImportedFunction(config), Some(func_idx))
+			if config.existing_import() == ExistingImport::Reject =>
+		{
+			return Err((module, Error::AlreadyInstrumented { func_idx }));
+		}
+		(Backend::ImportedFunction(config), Some(gas_func))
+			if charges_gas(&module, gas_func, config) =>
+		{
+			return Ok(module);
+		}
+		_ => {}
+	}
+
+	// The gas import is added after all other function imports and the gas global after all
+	// other globals.
+	let gas_meter = match backend {
+		Backend::ImportedFunction(config) => GasMeter::Function {
+			gas_func: gas_import.unwrap_or(func_imports),
+			argument_type: config.argument_type(),
+			extra_argument: config.extra_argument(),
+		},
+		Backend::MutableGlobal(_) => GasMeter::Global(module.globals_space() as u32),
+	};
+	let helper_costs = HelperCosts::new(&module, rules, gas_meter);
+
+	// Determine the metered blocks of all functions before modifying anything, so that the module
+	// is returned untouched on failure.
+	let bodies = module.code_section().map(|cs| cs.bodies()).unwrap_or(&[]);
+	let metered_blocks = bodies
+		.iter()
+		.enumerate()
+		.map(|(i, func_body)| {
+			determine_metered_blocks(func_body.code(), rules, &call_costs, &helper_costs, max_charge)
+				.map_err(|failure| failure.into_error(func_imports + i as u32))
+		})
+		.collect::<Result<Vec<_>, _>>();
+	let metered_blocks = match metered_blocks {
+		Ok(metered_blocks) => metered_blocks,
+		Err(error) => return Err((module, error)),
+	};
+
+	let mut module = match backend {
+		Backend::ImportedFunction(config) if gas_import.is_none() => {
+			let (module, gas_func) = add_gas_import(module, config);
+			debug_assert_eq!(gas_func, func_imports);
+			module
+		}
+		Backend::ImportedFunction(_) => module,
+		Backend::MutableGlobal(export_name) => add_gas_global(module, export_name).0,
+	};
+
+	let total_func = module.functions_space() as u32;
+	let mut need_grow_counter = false;
+
+	if let Some(code_section) = module.code_section_mut() {
+		for (func_body, blocks) in code_section.bodies_mut().iter_mut().zip(metered_blocks) {
+			insert_metering_calls(func_body.code_mut(), blocks, gas_meter);
+			if rules.memory_grow_cost().is_some()
+				&& inject_grow_counter(func_body.code_mut(), total_func) > 0
+			{
+				need_grow_counter = true;
+			}
+		}
+	}
+
+	let module = if need_grow_counter { add_grow_counter(module, rules, gas_meter) } else { module };
+	// The helpers for bulk instructions are added after the one for `memory.grow`.
+	#[cfg(feature = "bulk")]
+	let module = bulk::add_bulk_counters(
+		module,
+		rules,
+		gas_meter,
+		helper_costs.metered_bulk(),
+		total_func + need_grow_counter as u32,
+	);
+	Ok(module)
+}
+
+/// Returns the function index of the import matching `config` if there is one.
+fn find_gas_import(module: &elements::Module, config: &GasInjectionConfig) -> Option<u32> {
+	let types = module.type_section().map(|ts| ts.types()).unwrap_or(&[]);
+	let params = config.params().collect::<Vec<_>>();
+
+	module.import_section()?
+		.entries()
+		.iter()
+		.filter_map(|entry| match entry.external() {
+			elements::External::Function(type_idx) => Some((entry, *type_idx)),
+			_ => None,
+		})
+		.position(|(entry, type_idx)| {
+			entry.module() == config.module() && entry.field() == config.field() &&
+				match types.get(type_idx as usize) {
+					Some(elements::Type::Function(func_type)) =>
+						func_type.params() == &params[..] && func_type.results().is_empty(),
+					None => false,
+				}
+		})
+		.map(|func_idx| func_idx as u32)
+}
+
+/// Adds the gas import described by `config` to the module and returns its function index.
+///
+/// All references to functions with an index greater or equal to the one of the import are
+/// shifted by one.
+fn add_gas_import(
+	module: elements::Module,
+	config: &GasInjectionConfig,
+) -> (elements::Module, u32) {
 	// Injecting gas counting external
 	let mut mbuilder = builder::from_module(module);
 	let import_sig = mbuilder.push_signature(
 		builder::signature()
-			.with_param(ValueType::I32)
+			.with_params(config.params().collect())
 			.build_sig()
 		);
 
 	mbuilder.push_import(
 		builder::import()
-			.module(gas_module_name)
-			.field("gas")
+			.module(config.module())
+			.field(config.field())
 			.external().func(import_sig)
 			.build()
 		);
@@ -460,54 +1115,35 @@ pub fn inject_gas_counter<R: Rules>(
 	//    (subtract all imports that are NOT functions)
 
 	let gas_func = module.import_count(elements::ImportCountType::Function) as u32 - 1;
-	let total_func = module.functions_space() as u32;
-	let mut need_grow_counter = false;
-	let mut error = false;
-
-	// Updating calling addresses (all calls to function index >= `gas_func` should be incremented)
-	for section in module.sections_mut() {
-		match section {
-			elements::Section::Code(code_section) => {
-				for func_body in code_section.bodies_mut() {
-					update_call_index(func_body.code_mut(), gas_func);
-					if inject_counter(func_body.code_mut(), rules, gas_func).is_err() {
-						error = true;
-						break;
-					}
-					if rules.memory_grow_cost().is_some()
-						&& inject_grow_counter(func_body.code_mut(), total_func) > 0
-					{
-						need_grow_counter = true;
-					}
-				}
-			},
-			elements::Section::Export(export_section) => {
-				for export in export_section.entries_mut() {
-					if let elements::Internal::Function(func_index) = export.internal_mut() {
-						if *func_index >= gas_func { *func_index += 1}
-					}
-				}
-			},
-			elements::Section::Element(elements_section) => {
-				// Note that we do not need to check the element type referenced because in the
-				// WebAssembly 1.0 spec, the only allowed element type is funcref.
-				for segment in elements_section.entries_mut() {
-					// update all indirect call addresses initial values
-					for func_index in segment.members_mut() {
-						if *func_index >= gas_func { *func_index += 1}
-					}
-				}
-			},
-			elements::Section::Start(start_idx) => {
-				if *start_idx >= gas_func { *start_idx += 1}
-			},
-			_ => { }
-		}
-	}
 
-	if error { return Err(module); }
+	// All references to functions with index >= `gas_func` should be incremented
+	IndexRemap::new()
+		.with_functions(IndexMapping::Inserted { index: gas_func, count: 1 })
+		.apply(&mut module);
+
+	(module, gas_func)
+}
+
+/// Adds an exported mutable i64 global which holds the remaining gas and returns its index.
+fn add_gas_global(module: elements::Module, export_name: &str) -> (elements::Module, u32) {
+	let imported_globals = module.import_count(elements::ImportCountType::Global) as u32;
+
+	let mut mbuilder = builder::from_module(module);
+	let gas_global = imported_globals + mbuilder.push_global(
+		builder::global()
+			.value_type().i64()
+			.mutable()
+			.init_expr(elements::Instruction::I64Const(0))
+			.build()
+	);
+	mbuilder.push_export(
+		builder::export()
+			.field(export_name)
+			.internal().global(gas_global)
+			.build()
+	);
 
-	if need_grow_counter { Ok(add_grow_counter(module, rules, gas_func)) } else { Ok(module) }
+	(mbuilder.build(), gas_global)
 }
 
 #[cfg(test)]
@@ -554,10 +1190,12 @@ mod tests {
 			"env",
 		).unwrap();
 
+		// The helper is charged along with `memory.grow`: 14 for its most expensive path, which
+		// traps, minus the `memory.grow` it executes.
 		assert_eq!(
 			get_function_body(&injected_module, 0).unwrap(),
 			&vec![
-				I32Const(2),
+				I32Const(2 + 13),
 				Call(0),
 				GetGlobal(0),
 				Call(2),
@@ -568,10 +1206,19 @@ mod tests {
 			get_function_body(&injected_module, 1).unwrap(),
 			&vec![
 				GetLocal(0),
-				GetLocal(0),
-				I32Const(10000),
-				I32Mul,
+				I64ExtendUI32,
+				I64Const(10000),
+				I64Mul,
+				TeeLocal(1),
+				I64Const(i32::MAX as i64),
+				I64GtU,
+				If(elements::BlockType::NoResult),
+				Unreachable,
+				End,
+				GetLocal(1),
+				I32WrapI64,
 				Call(0),
+				GetLocal(0),
 				GrowMemory(0),
 				End,
 			][..]
@@ -582,7 +1229,7 @@ mod tests {
 	}
 
 	#[test]
-	fn grow_no_gas_no_track() {
+	fn simple_grow_mutable_global() {
 		let module = builder::module()
 			.global()
 				.value_type().i32()
@@ -601,20 +1248,424 @@ mod tests {
 				.build()
 			.build();
 
-		let injected_module = inject_gas_counter(module, &rules::Set::default(), "env").unwrap();
+		let injected_module = inject_gas_counter_with_backend(
+			module,
+			&rules::Set::default().with_grow_cost(10000),
+			Backend::MutableGlobal("gas_left"),
+		).unwrap();
 
 		assert_eq!(
 			get_function_body(&injected_module, 0).unwrap(),
 			&vec![
-				I32Const(2),
-				Call(0),
+				GetGlobal(1),
+				I64Const(2 + 14),
+				I64LtU,
+				If(elements::BlockType::NoResult),
+				Unreachable,
+				End,
+				GetGlobal(1),
+				I64Const(2 + 14),
+				I64Sub,
+				SetGlobal(1),
 				GetGlobal(0),
-				GrowMemory(0),
+				Call(1),
 				End
 			][..]
 		);
+		assert_eq!(
+			get_function_body(&injected_module, 1).unwrap(),
+			&vec![
+				GetLocal(0),
+				I64ExtendUI32,
+				I64Const(10000),
+				I64Mul,
+				TeeLocal(1),
+				GetGlobal(1),
+				I64GtU,
+				If(elements::BlockType::NoResult),
+				Unreachable,
+				End,
+				GetGlobal(1),
+				GetLocal(1),
+				I64Sub,
+				SetGlobal(1),
+				GetLocal(0),
+				GrowMemory(0),
+				End,
+			][..]
+		);
 
-		assert_eq!(injected_module.functions_space(), 2);
+		let exports = injected_module.export_section().unwrap().entries();
+		assert_eq!(exports.len(), 1);
+		assert_eq!(exports[0].field(), "gas_left");
+		assert_eq!(exports[0].internal(), &elements::Internal::Global(1));
+
+		let binary = serialize(injected_module).expect("serialization failed");
+		self::wabt::wasm2wat(&binary).unwrap();
+	}
+
+	/// Charges 1 for every instruction and the given cost for growing the memory.
+	struct GrowRules(rules::MemoryGrowCost);
+
+	impl Rules for GrowRules {
+		fn instruction_cost(&self, _: &elements::Instruction) -> Option<u64> {
+			Some(1)
+		}
+
+		fn memory_grow_cost(&self) -> Option<rules::MemoryGrowCost> {
+			Some(self.0.clone())
+		}
+	}
+
+	fn grow_counter_body(cost: rules::MemoryGrowCost) -> Vec<elements::Instruction> {
+		let module = parse_wat(r#"
+(module
+	(memory 1)
+	(func (param i32) (result i32)
+		(memory.grow (get_local 0))))
+"#);
+		let injected_module = inject_gas_counter(module, &GrowRules(cost), "env").unwrap();
+
+		let binary = serialize(injected_module.clone()).expect("serialization failed");
+		wabt::Module::read_binary(&binary, &Default::default())
+			.and_then(|module| module.validate())
+			.expect("Injected module must be valid");
+
+		get_function_body(&injected_module, 1).unwrap().to_vec()
+	}
+
+	#[test]
+	fn tiered_grow() {
+		let nz = |val| NonZeroU32::new(val).unwrap();
+		let body = grow_counter_body(rules::MemoryGrowCost::Tiered(vec![
+			(1, nz(10)),
+			(16, nz(20)),
+			(u32::MAX, nz(30)),
+		]));
+
+		assert_eq!(
+			body,
+			vec![
+				GetLocal(0),
+				I32Const(1),
+				I32LeU,
+				If(elements::BlockType::Value(ValueType::I64)),
+				GetLocal(0),
+				I64ExtendUI32,
+				I64Const(10),
+				I64Mul,
+				Else,
+				GetLocal(0),
+				I32Const(16),
+				I32LeU,
+				If(elements::BlockType::Value(ValueType::I64)),
+				GetLocal(0),
+				I64ExtendUI32,
+				I64Const(20),
+				I64Mul,
+				Else,
+				GetLocal(0),
+				I64ExtendUI32,
+				I64Const(30),
+				I64Mul,
+				End,
+				End,
+				TeeLocal(1),
+				I64Const(i32::MAX as i64),
+				I64GtU,
+				If(elements::BlockType::NoResult),
+				Unreachable,
+				End,
+				GetLocal(1),
+				I32WrapI64,
+				Call(0),
+				GetLocal(0),
+				GrowMemory(0),
+				End,
+			]
+		);
+	}
+
+	#[test]
+	fn capped_total_size_grow() {
+		let body = grow_counter_body(rules::MemoryGrowCost::Capped {
+			max_pages: 16,
+			cost: Box::new(rules::MemoryGrowCost::TotalSize(NonZeroU32::new(100).unwrap())),
+		});
+
+		assert_eq!(
+			body,
+			vec![
+				GetLocal(0),
+				I32Const(16),
+				I32GtU,
+				If(elements::BlockType::NoResult),
+				Unreachable,
+				End,
+				CurrentMemory(0),
+				I64ExtendUI32,
+				GetLocal(0),
+				I32Const(65536),
+				GetLocal(0),
+				I32Const(65536),
+				I32LtU,
+				Select,
+				I64ExtendUI32,
+				I64Add,
+				I64Const(100),
+				I64Mul,
+				TeeLocal(1),
+				I64Const(i32::MAX as i64),
+				I64GtU,
+				If(elements::BlockType::NoResult),
+				Unreachable,
+				End,
+				GetLocal(1),
+				I32WrapI64,
+				Call(0),
+				GetLocal(0),
+				GrowMemory(0),
+				End,
+			]
+		);
+	}
+
+	#[test]
+	fn empty_tiers_grow() {
+		let body = grow_counter_body(rules::MemoryGrowCost::Tiered(Vec::new()));
+		assert_eq!(body[..2], [I64Const(0), TeeLocal(1)]);
+	}
+
+	/// Executes the body of a `memory.grow` helper with a linear cost, requesting `pages`, and
+	/// returns the amount passed to the gas function or `None` if the helper traps before.
+	fn execute_grow_charge(body: &[elements::Instruction], pages: u32) -> Option<u64> {
+		// All values are kept as `u64`, with `i32` values zero extended.
+		let mut locals = [pages as u64, 0];
+		let mut stack: Vec<u64> = Vec::new();
+		let mut instructions = body.iter();
+		while let Some(instruction) = instructions.next() {
+			match instruction {
+				GetLocal(idx) => stack.push(locals[*idx as usize]),
+				TeeLocal(idx) => locals[*idx as usize] = *stack.last()?,
+				I64Const(value) => stack.push(*value as u64),
+				I64ExtendUI32 => {}
+				I32WrapI64 => {
+					let value = stack.pop()?;
+					stack.push(value as u32 as u64);
+				}
+				I64Mul | I64GtU => {
+					let (rhs, lhs) = (stack.pop()?, stack.pop()?);
+					stack.push(match instruction {
+						I64Mul => lhs.wrapping_mul(rhs),
+						_ => (lhs > rhs) as u64,
+					});
+				}
+				If(_) => {
+					if stack.pop()? == 0 {
+						instructions.find(|instruction| **instruction == End);
+					}
+				}
+				End => {}
+				Unreachable => return None,
+				Call(0) => return stack.pop(),
+				_ => panic!("Unexpected instruction {:?}", instruction),
+			}
+		}
+		panic!("The gas function is never called")
+	}
+
+	#[test]
+	fn grow_charge_overflow() {
+		let module = parse_wat(r#"
+(module
+	(memory 1)
+	(func (param i32) (result i32)
+		(memory.grow (get_local 0))))
+"#);
+		let rules = GrowRules(rules::MemoryGrowCost::Linear(NonZeroU32::new(u32::MAX).unwrap()));
+		let helper_body = |config: &GasInjectionConfig| {
+			let injected_module = inject_gas_counter_with_backend(
+				module.clone(),
+				&rules,
+				Backend::ImportedFunction(config),
+			).unwrap();
+			get_function_body(&injected_module, 1).unwrap().to_vec()
+		};
+
+		// Two pages cost `2 * u32::MAX`, which wraps around to `u32::MAX - 1` in 32 bits.
+		let body = helper_body(&GasInjectionConfig::new("env").with_argument_type(GasArgument::I64));
+		assert_eq!(execute_grow_charge(&body, 2), Some(2 * u32::MAX as u64));
+
+		// An `i32` amount can't hold the charge, so the helper traps instead of charging less.
+		let body = helper_body(&GasInjectionConfig::new("env"));
+		assert_eq!(execute_grow_charge(&body, 2), None);
+		assert_eq!(execute_grow_charge(&body, 1), None);
+		assert_eq!(execute_grow_charge(&body, 0), Some(0));
+	}
+
+	#[cfg(feature = "bulk")]
+	struct BulkRules;
+
+	#[cfg(feature = "bulk")]
+	impl Rules for BulkRules {
+		fn instruction_cost(&self, _: &elements::Instruction) -> Option<u64> {
+			Some(1)
+		}
+
+		fn memory_grow_cost(&self) -> Option<rules::MemoryGrowCost> {
+			Some(rules::MemoryGrowCost::Linear(NonZeroU32::new(10).unwrap()))
+		}
+
+		fn bulk_cost(&self, instruction: &elements::BulkInstruction) -> Option<NonZeroU32> {
+			match instruction {
+				elements::BulkInstruction::MemoryCopy => NonZeroU32::new(3),
+				elements::BulkInstruction::MemoryFill => NonZeroU32::new(2),
+				_ => None,
+			}
+		}
+	}
+
+	#[cfg(feature = "bulk")]
+	#[test]
+	fn bulk_helpers() {
+		let mut features = wabt::Features::new();
+		features.enable_bulk_memory();
+		let binary = wabt::wat2wasm_with_features(r#"
+(module
+	(memory 1)
+	(func (param i32 i32 i32) (result i32)
+		(memory.fill (get_local 0) (get_local 1) (get_local 2))
+		(memory.fill (get_local 1) (get_local 0) (get_local 2))
+		(memory.grow (get_local 0))))
+"#, features.clone()).expect("Failed to wat2wasm");
+		let module = elements::deserialize_buffer(&binary).expect("Failed to deserialize the module");
+
+		let injected_module = inject_gas_counter(module, &BulkRules, "env").unwrap();
+
+		// Validates the module, as reading binaries with bulk memory operations isn't supported.
+		let binary = serialize(injected_module.clone()).expect("serialization failed");
+		wabt::wasm2wat_with_features(&binary, features.clone())
+			.and_then(|wat| wabt::wat2wasm_with_features(wat, features))
+			.expect("Injected module must be valid");
+
+		// The helper for `memory.grow` comes first, followed by one per metered bulk instruction.
+		let calls = get_function_body(&injected_module, 0)
+			.unwrap()
+			.iter()
+			.filter_map(|instruction| match instruction {
+				Call(func_idx) if *func_idx != 0 => Some(*func_idx),
+				_ => None,
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(calls, vec![3, 3, 2]);
+
+		assert_eq!(
+			get_function_body(&injected_module, 2).unwrap(),
+			&vec![
+				GetLocal(2),
+				I64ExtendUI32,
+				I64Const(2),
+				I64Mul,
+				TeeLocal(3),
+				I64Const(i32::MAX as i64),
+				I64GtU,
+				If(elements::BlockType::NoResult),
+				Unreachable,
+				End,
+				GetLocal(3),
+				I32WrapI64,
+				Call(0),
+				GetLocal(0),
+				GetLocal(1),
+				GetLocal(2),
+				Bulk(elements::BulkInstruction::MemoryFill),
+				End,
+			][..]
+		);
+	}
+
+	#[test]
+	fn mutable_global_keeps_call_index() {
+		let module = builder::module()
+			.function()
+				.signature().param().i32().build()
+				.body().build()
+				.build()
+			.function()
+				.signature().param().i32().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							Call(0),
+							Call(0),
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		let injected_module = inject_gas_counter_with_backend(
+			module,
+			&rules::Set::default(),
+			Backend::MutableGlobal("gas_left"),
+		).unwrap();
+
+		assert_eq!(injected_module.functions_space(), 2);
+		assert_eq!(
+			get_function_body(&injected_module, 1).unwrap(),
+			&vec![
+				GetGlobal(0),
+				I64Const(2),
+				I64LtU,
+				If(elements::BlockType::NoResult),
+				Unreachable,
+				End,
+				GetGlobal(0),
+				I64Const(2),
+				I64Sub,
+				SetGlobal(0),
+				Call(0),
+				Call(0),
+				End
+			][..]
+		);
+	}
+
+	#[test]
+	fn grow_no_gas_no_track() {
+		let module = builder::module()
+			.global()
+				.value_type().i32()
+				.build()
+			.function()
+				.signature().param().i32().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							GetGlobal(0),
+							GrowMemory(0),
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		let injected_module = inject_gas_counter(module, &rules::Set::default(), "env").unwrap();
+
+		assert_eq!(
+			get_function_body(&injected_module, 0).unwrap(),
+			&vec![
+				I32Const(2),
+				Call(0),
+				GetGlobal(0),
+				GrowMemory(0),
+				End
+			][..]
+		);
+
+		assert_eq!(injected_module.functions_space(), 2);
 
 		let binary = serialize(injected_module).expect("serialization failed");
 		self::wabt::wasm2wat(&binary).unwrap();
@@ -678,6 +1729,38 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn import_call_cost() {
+		let module = parse_wat(r#"
+(module
+	(import "env" "ext_print" (func $print (param i32)))
+	(import "env" "ext_hash" (func $hash))
+	(func $f
+		(call $hash)
+		(call $print (i32.const 0))
+		(call $f)))
+"#);
+
+		let rules = rules::Set::default()
+			.with_import_call_cost("env", "ext_hash", 100)
+			.with_import_call_cost("env", "ext_nonexistent", 1000);
+		let injected_module = inject_gas_counter(module, &rules, "env").unwrap();
+
+		// The gas import is appended after the other imports, shifting `$f` to index 3.
+		assert_eq!(
+			get_function_body(&injected_module, 0).unwrap(),
+			&vec![
+				I32Const(104),
+				Call(2),
+				Call(1),
+				I32Const(0),
+				Call(0),
+				Call(3),
+				End,
+			][..]
+		);
+	}
+
 	#[test]
 	fn forbidden() {
 		let module = builder::module()
@@ -699,12 +1782,292 @@ mod tests {
 
 		let rules = rules::Set::default().with_forbidden_floats();
 
+		match inject_gas_counter(module, &rules, "env") {
+			Ok(_) => panic!("Should be error because of the forbidden operation"),
+			Err((_, error)) => assert_eq!(
+				error,
+				Error::Function {
+					func_idx: 0,
+					offset: 0,
+					instruction: F32Const(555555),
+					kind: ErrorKind::ForbiddenInstruction,
+				}
+			),
+		}
+	}
+
+	#[test]
+	fn cost_overflow() {
+		let module = builder::module()
+			.function()
+				.signature().build()
+				.body().build()
+				.build()
+			.function()
+				.signature().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							Nop,
+							Nop,
+							Nop,
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
 
-		if inject_gas_counter(module, &rules, "env").is_ok() {
-			panic!("Should be error because of the forbidden operation")
+		let rules = rules::Set::new(u32::MAX / 2, Default::default());
+
+		match inject_gas_counter(module, &rules, "env") {
+			Ok(_) => panic!("Should be error because of the cost overflow"),
+			Err((module, error)) => {
+				assert_eq!(
+					error,
+					Error::Function {
+						func_idx: 1,
+						offset: 1,
+						instruction: Nop,
+						kind: ErrorKind::CostOverflow,
+					}
+				);
+				// The module is returned untouched.
+				assert_eq!(module.import_count(elements::ImportCountType::Function), 0);
+				assert_eq!(module.code_section().unwrap().bodies()[0].code().elements(), &[End]);
+				assert_eq!(
+					module.code_section().unwrap().bodies()[1].code().elements(),
+					&[Nop, Nop, Nop, End],
+				);
+			}
 		}
 	}
 
+	#[test]
+	fn i64_gas_import() {
+		let module = parse_wat(r#"
+(module
+	(memory 1)
+	(func (param i32) (result i32)
+		(nop)
+		(nop)
+		(memory.grow (get_local 0))))
+"#);
+
+		let rules = rules::Set::new(u32::MAX, Default::default()).with_grow_cost(u32::MAX);
+		let injected_module = inject_gas_counter_with_backend(
+			module,
+			&rules,
+			Backend::ImportedFunction(&GasInjectionConfig::new("env").with_argument_type(GasArgument::I64)),
+		).unwrap();
+
+		let import_type = injected_module.type_section().unwrap().types().last().unwrap();
+		assert_eq!(
+			import_type,
+			&elements::Type::Function(elements::FunctionType::new(vec![ValueType::I64], vec![])),
+		);
+		assert_eq!(
+			get_function_body(&injected_module, 0).unwrap(),
+			&vec![
+				// Each of the 6 instructions of the helper besides `memory.grow` costs `u32::MAX`.
+				I64Const((4 + 6) * u32::MAX as i64),
+				Call(0),
+				Nop,
+				Nop,
+				GetLocal(0),
+				Call(2),
+				End,
+			][..]
+		);
+		assert_eq!(
+			get_function_body(&injected_module, 1).unwrap(),
+			&vec![
+				GetLocal(0),
+				I64ExtendUI32,
+				I64Const(u32::MAX as i64),
+				I64Mul,
+				Call(0),
+				GetLocal(0),
+				GrowMemory(0),
+				End,
+			][..]
+		);
+
+		let binary = serialize(injected_module).expect("serialization failed");
+		self::wabt::wasm2wat(&binary).unwrap();
+	}
+
+	#[test]
+	fn configured_import() {
+		let module = parse_wat(r#"
+(module
+	(memory 1)
+	(func (param i32) (result i32)
+		(memory.grow (get_local 0))))
+"#);
+
+		let config = GasInjectionConfig::new("seal0")
+			.with_field("charge_gas")
+			.with_argument_type(GasArgument::I64)
+			.with_extra_argument(7);
+		let injected_module = inject_gas_counter_with_backend(
+			module,
+			&rules::Set::default().with_grow_cost(10),
+			Backend::ImportedFunction(&config),
+		).unwrap();
+
+		let import = &injected_module.import_section().unwrap().entries()[0];
+		assert_eq!((import.module(), import.field()), ("seal0", "charge_gas"));
+		assert_eq!(
+			injected_module.type_section().unwrap().types().last().unwrap(),
+			&elements::Type::Function(elements::FunctionType::new(
+				vec![ValueType::I64, ValueType::I32],
+				vec![],
+			)),
+		);
+		assert_eq!(
+			get_function_body(&injected_module, 0).unwrap(),
+			&vec![
+				I64Const(2 + 7),
+				I32Const(7),
+				Call(0),
+				GetLocal(0),
+				Call(2),
+				End,
+			][..]
+		);
+		assert_eq!(
+			get_function_body(&injected_module, 1).unwrap(),
+			&vec![
+				GetLocal(0),
+				I64ExtendUI32,
+				I64Const(10),
+				I64Mul,
+				I32Const(7),
+				Call(0),
+				GetLocal(0),
+				GrowMemory(0),
+				End,
+			][..]
+		);
+
+		let binary = serialize(injected_module).expect("serialization failed");
+		self::wabt::wasm2wat(&binary).unwrap();
+	}
+
+	#[test]
+	fn reuse_existing_import() {
+		let module = parse_wat(r#"
+(module
+	(import "env" "gas" (func (param i32)))
+	(func $f (export "f")
+		(call $f)))
+"#);
+
+		let injected_module = inject_gas_counter(module, &rules::Set::default(), "env").unwrap();
+
+		assert_eq!(injected_module.import_count(elements::ImportCountType::Function), 1);
+		assert_eq!(
+			get_function_body(&injected_module, 0).unwrap(),
+			&vec![
+				I32Const(1),
+				Call(0),
+				Call(1),
+				End,
+			][..]
+		);
+	}
+
+	#[test]
+	fn reuse_is_idempotent() {
+		let module = parse_wat(r#"
+(module
+	(memory 1)
+	(func $f (export "f") (param i32) (result i32)
+		(if (result i32) (get_local 0)
+			(then (memory.grow (i32.const 1)))
+			(else (call $f (i32.const 0))))))
+"#);
+
+		let rules = rules::Set::default().with_grow_cost(10);
+		let inject = |module| inject_gas_counter(module, &rules, "env");
+
+		let once = inject(module).unwrap();
+		let twice = inject(once.clone()).unwrap();
+
+		assert_eq!(serialize(twice).unwrap(), serialize(once).unwrap());
+	}
+
+	#[test]
+	fn mismatching_import_is_not_reused() {
+		let module = parse_wat(r#"
+(module
+	(import "env" "gas" (func (param i64)))
+	(func))
+"#);
+
+		let injected_module = inject_gas_counter(module, &rules::Set::default(), "env").unwrap();
+		assert_eq!(injected_module.import_count(elements::ImportCountType::Function), 2);
+	}
+
+	#[test]
+	fn reject_leaves_module_unchanged() {
+		let module = parse_wat(r#"
+(module
+	(memory 1)
+	(func $f (export "f") (param i32) (result i32)
+		(if (result i32) (get_local 0)
+			(then (memory.grow (i32.const 1)))
+			(else (call $f (i32.const 0))))))
+"#);
+
+		let rules = rules::Set::default().with_grow_cost(10);
+		let config = GasInjectionConfig::new("env").with_existing_import(ExistingImport::Reject);
+		let inject = |module| inject_gas_counter_with_backend(
+			module,
+			&rules,
+			Backend::ImportedFunction(&config),
+		);
+
+		let once = inject(module).unwrap();
+		let (twice, error) = inject(once.clone()).unwrap_err();
+
+		assert_eq!(error, Error::AlreadyInstrumented { func_idx: 0 });
+		assert_eq!(serialize(twice).unwrap(), serialize(once).unwrap());
+	}
+
+	#[test]
+	fn malformed_control_stack() {
+		let module = builder::module()
+			.function()
+				.signature().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							Block(elements::BlockType::NoResult),
+							Br(2),
+							End,
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		let error = inject_gas_counter(module, &rules::Set::default(), "env")
+			.expect_err("Should be error because of the out of bounds label")
+			.1;
+		assert_eq!(
+			error,
+			Error::Function {
+				func_idx: 0,
+				offset: 1,
+				instruction: Br(2),
+				kind: ErrorKind::MalformedControlStack,
+			}
+		);
+	}
+
 	fn parse_wat(source: &str) -> elements::Module {
 		let module_bytes = wabt::Wat2Wasm::new()
 			.validate(false)
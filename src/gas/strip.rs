@@ -0,0 +1,624 @@
+//! This module is used to remove the gas metering code injected by `inject_gas_counter`.
+
+use crate::std::fmt;
+use crate::std::vec::Vec;
+
+use parity_wasm::elements::{self, BlockType, Instruction, ValueType};
+use crate::remap::{IndexMapping, IndexRemap};
+use super::{find_gas_import, GasArgument, GasInjectionConfig, MAX_PAGES};
+
+/// Error that occured while removing the gas metering code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StripError {
+	/// The module doesn't import the gas function described by the config.
+	NotInstrumented,
+	/// The gas function is called in a way the gas metering injection never does.
+	UnrecognizedGasCall {
+		/// Index of the function in the function index space of the instrumented module.
+		func_idx: u32,
+		/// Position of the call in the function body.
+		offset: usize,
+	},
+}
+
+impl fmt::Display for StripError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			StripError::NotInstrumented => write!(f, "Module doesn't import the gas function"),
+			StripError::UnrecognizedGasCall { func_idx, offset } => write!(
+				f,
+				"Unrecognized call to the gas function in function {} at instruction {}",
+				func_idx, offset,
+			),
+		}
+	}
+}
+
+/// What has to be done to a function body to remove the metering code.
+enum Metering {
+	/// The body charges gas at the listed positions of the charging sequences.
+	Charges(Vec<usize>),
+	/// The body is a helper charging gas for the wrapped instruction, e.g. `memory.grow`.
+	Helper(Instruction),
+}
+
+/// Transforms a module instrumented by `inject_gas_counter` back into the original module.
+///
+/// The gas function is identified by `config`, which has to be the same as the one used for the
+/// instrumentation. Every charge of a static amount is removed, calls to the helpers charging for
+/// `memory.grow` and bulk operations are replaced by the original instructions again and the gas
+/// import is deleted. All function indices are restored. The signatures of the gas import and the
+/// helpers are removed from the type section if they aren't used anymore and located at its end,
+/// which is where the instrumentation adds the types the module doesn't have already.
+///
+/// Modules instrumented using `Backend::MutableGlobal` are not supported.
+///
+/// The function fails if the gas function is not imported or called in any other way than the
+/// instrumentation does, returning the unmodified module along with the `StripError`.
+pub fn strip_gas_counter(
+	mut module: elements::Module,
+	config: &GasInjectionConfig,
+)
+	-> Result<elements::Module, (elements::Module, StripError)>
+{
+	let gas_func = match find_gas_import(&module, config) {
+		Some(gas_func) => gas_func,
+		None => return Err((module, StripError::NotInstrumented)),
+	};
+	let func_imports = module.import_count(elements::ImportCountType::Function) as u32;
+
+	// Find all metering code before modifying anything, so that the module is returned untouched
+	// on failure.
+	let mut meterings = Vec::new();
+	// Function indices of the helpers along with the instruction they wrap.
+	let mut helpers: Vec<(u32, Instruction)> = Vec::new();
+	let bodies = module.code_section().map(|cs| cs.bodies()).unwrap_or(&[]);
+	for (i, func_body) in bodies.iter().enumerate() {
+		let func_idx = func_imports + i as u32;
+		match find_metering(func_body, function_type(&module, i), gas_func, config) {
+			Ok(Metering::Helper(wrapped)) if helpers.iter().all(|(_, other)| *other != wrapped) => {
+				helpers.push((func_idx, wrapped.clone()));
+				meterings.push(Metering::Helper(wrapped));
+			}
+			Ok(Metering::Helper(_)) => {
+				let offset = func_body.code().elements().iter()
+					.position(|instruction| *instruction == Instruction::Call(gas_func))
+					.unwrap_or(0);
+				return Err((module, StripError::UnrecognizedGasCall { func_idx, offset }));
+			}
+			Ok(metering) => meterings.push(metering),
+			Err(offset) => {
+				return Err((module, StripError::UnrecognizedGasCall { func_idx, offset }));
+			}
+		}
+	}
+
+	let charge_len = config.params().count() + 1;
+	if let Some(code_section) = module.code_section_mut() {
+		for (func_body, metering) in code_section.bodies_mut().iter_mut().zip(meterings) {
+			let positions = match metering {
+				Metering::Charges(positions) => positions,
+				Metering::Helper(_) => continue,
+			};
+			let instructions = func_body.code_mut().elements_mut();
+			let mut removed = vec![false; instructions.len()];
+			for start in positions {
+				removed[start..start + charge_len].iter_mut().for_each(|removed| *removed = true);
+			}
+			let mut removed = removed.into_iter();
+			instructions.retain(|_| !removed.next().expect("one flag per instruction; qed"));
+			for instruction in instructions.iter_mut() {
+				let wrapped = helpers.iter()
+					.find(|(helper, _)| *instruction == Instruction::Call(*helper))
+					.map(|(_, wrapped)| wrapped.clone());
+				if let Some(wrapped) = wrapped {
+					*instruction = wrapped;
+				}
+			}
+		}
+	}
+
+	// Types of the gas import and the helpers, which may have been added by the instrumentation.
+	let mut added_types = Vec::new();
+	if let Some(function_section) = module.function_section() {
+		added_types.extend(helpers.iter().map(|(helper, _)| {
+			function_section.entries()[(helper - func_imports) as usize].type_ref()
+		}));
+	}
+
+	// Helpers are found in ascending order, so removing them in reverse keeps the positions of
+	// the remaining ones.
+	for (helper, _) in helpers.iter().rev() {
+		let defined_idx = (helper - func_imports) as usize;
+		if let Some(function_section) = module.function_section_mut() {
+			function_section.entries_mut().remove(defined_idx);
+		}
+		if let Some(code_section) = module.code_section_mut() {
+			code_section.bodies_mut().remove(defined_idx);
+		}
+	}
+
+	if let Some(import_section) = module.import_section_mut() {
+		let import_idx = import_section.entries()
+			.iter()
+			.enumerate()
+			.filter(|(_, entry)| matches!(entry.external(), elements::External::Function(_)))
+			.nth(gas_func as usize)
+			.map(|(import_idx, _)| import_idx)
+			.expect("gas_func is the index of a function import; qed");
+		let import = import_section.entries_mut().remove(import_idx);
+		if let elements::External::Function(type_idx) = import.external() {
+			added_types.push(*type_idx);
+		}
+	}
+
+	let removed = Some(gas_func)
+		.into_iter()
+		.chain(helpers.iter().map(|(helper, _)| *helper))
+		.collect();
+	IndexRemap::new()
+		.with_functions(IndexMapping::removed(removed))
+		.apply(&mut module);
+
+	remove_added_types(&mut module, &added_types);
+	remove_empty_sections(&mut module);
+
+	Ok(module)
+}
+
+/// Returns whether any function body of `module` charges gas by calling the gas function
+/// `gas_func` the way the instrumentation does, i.e. with a constant amount or from a helper
+/// charging for `memory.grow` or a bulk operation.
+pub(crate) fn charges_gas(
+	module: &elements::Module,
+	gas_func: u32,
+	config: &GasInjectionConfig,
+) -> bool {
+	let bodies = module.code_section().map(|cs| cs.bodies()).unwrap_or(&[]);
+	bodies.iter().enumerate().any(|(i, func_body)| {
+		match find_metering(func_body, function_type(module, i), gas_func, config) {
+			Ok(Metering::Charges(positions)) => !positions.is_empty(),
+			Ok(Metering::Helper(_)) => true,
+			Err(_) => false,
+		}
+	})
+}
+
+/// Returns the signature of the function defined at `defined_idx`.
+fn function_type(module: &elements::Module, defined_idx: usize) -> Option<&elements::FunctionType> {
+	let type_ref = module.function_section()?.entries().get(defined_idx)?.type_ref();
+	match module.type_section()?.types().get(type_ref as usize)? {
+		elements::Type::Function(func_type) => Some(func_type),
+	}
+}
+
+/// Finds the metering code in `func_body` of the type `func_type`, returning the offset of the
+/// first unrecognized call to the gas function on failure.
+fn find_metering(
+	func_body: &elements::FuncBody,
+	func_type: Option<&elements::FunctionType>,
+	gas_func: u32,
+	config: &GasInjectionConfig,
+) -> Result<Metering, usize> {
+	if let Some(wrapped) = func_type.and_then(|func_type| {
+		wrapped_instruction(func_body, func_type, gas_func, config)
+	}) {
+		return Ok(Metering::Helper(wrapped));
+	}
+
+	let instructions = func_body.code().elements();
+	let mut positions = Vec::new();
+	for (offset, instruction) in instructions.iter().enumerate() {
+		if *instruction != Instruction::Call(gas_func) {
+			continue;
+		}
+		positions.push(charge_start(instructions, offset, config).ok_or(offset)?);
+	}
+	Ok(Metering::Charges(positions))
+}
+
+/// Returns the instruction a helper charging for a dynamic cost executes after the charge, if
+/// `func_body` of the type `func_type` is exactly a helper as emitted by `add_metered_helper`.
+fn wrapped_instruction(
+	func_body: &elements::FuncBody,
+	func_type: &elements::FunctionType,
+	gas_func: u32,
+	config: &GasInjectionConfig,
+) -> Option<Instruction> {
+	use parity_wasm::elements::Instruction::*;
+
+	let scratch = func_type.params().len() as u32;
+	let (mut expected, locals) = match config.argument_type() {
+		GasArgument::I32 => (
+			vec![
+				TeeLocal(scratch),
+				I64Const(i32::MAX as i64),
+				I64GtU,
+				If(BlockType::NoResult),
+				Unreachable,
+				End,
+				GetLocal(scratch),
+				I32WrapI64,
+			],
+			vec![elements::Local::new(1, ValueType::I64)],
+		),
+		GasArgument::I64 => (Vec::new(), Vec::new()),
+	};
+	if func_body.locals() != &locals[..] {
+		return None;
+	}
+	expected.extend(config.extra_argument().map(I32Const));
+	expected.push(Call(gas_func));
+
+	let instructions = func_body.code().elements();
+	let (charge, wrapped) = match (func_type.params(), func_type.results(), instructions) {
+		([ValueType::I32], [ValueType::I32], [charge @ .., GetLocal(0), GrowMemory(0), End]) => {
+			(charge, GrowMemory(0))
+		}
+		#[cfg(feature = "bulk")]
+		(
+			[ValueType::I32, ValueType::I32, ValueType::I32],
+			[],
+			[charge @ .., GetLocal(0), GetLocal(1), GetLocal(2), Bulk(bulk), End],
+		) if super::bulk::is_sized(bulk) => (charge, Bulk(bulk.clone())),
+		_ => return None,
+	};
+	let computation = charge.strip_suffix(&expected[..])?;
+	let computation_len = match wrapped {
+		GrowMemory(_) => grow_charge_len(computation),
+		_ => per_unit_charge_len(computation, 2),
+	};
+	if computation_len != Some(computation.len()) {
+		return None;
+	}
+	Some(wrapped)
+}
+
+/// Returns the length of the computation of a `memory.grow` charge at the start of
+/// `instructions`, as emitted by `push_grow_charge` for any `MemoryGrowCost`.
+fn grow_charge_len(instructions: &[Instruction]) -> Option<usize> {
+	use parity_wasm::elements::Instruction::*;
+
+	const MAX: i32 = MAX_PAGES as i32;
+	match instructions {
+		// Capped
+		[
+			GetLocal(0), I32Const(_), I32GtU, If(BlockType::NoResult), Unreachable, End,
+			rest @ ..
+		] => Some(6 + grow_charge_len(rest)?),
+		// TotalSize
+		[
+			CurrentMemory(0), I64ExtendUI32,
+			GetLocal(0), I32Const(MAX), GetLocal(0), I32Const(MAX), I32LtU, Select, I64ExtendUI32,
+			I64Add, I64Const(_), I64Mul,
+			..
+		] => Some(12),
+		// Tiered without tiers
+		[I64Const(0), ..] => Some(1),
+		// Linear and Tiered
+		_ => tiers_len(instructions),
+	}
+}
+
+/// Returns the length of the tiered per page charge at the start of `instructions`.
+fn tiers_len(instructions: &[Instruction]) -> Option<usize> {
+	use parity_wasm::elements::Instruction::*;
+
+	match instructions {
+		[GetLocal(0), I32Const(_), I32LeU, If(BlockType::Value(ValueType::I64)), rest @ ..] => {
+			let then_len = per_unit_charge_len(rest, 0)?;
+			let rest = rest[then_len..].strip_prefix(&[Else])?;
+			let else_len = tiers_len(rest)?;
+			rest[else_len..].strip_prefix(&[End])?;
+			Some(4 + then_len + 1 + else_len + 1)
+		}
+		_ => per_unit_charge_len(instructions, 0),
+	}
+}
+
+/// Returns the length of the charge of a constant cost per page, byte or element, given by the
+/// parameter `local`, at the start of `instructions`.
+fn per_unit_charge_len(instructions: &[Instruction], local: u32) -> Option<usize> {
+	use parity_wasm::elements::Instruction::*;
+
+	match instructions {
+		[GetLocal(l), I64ExtendUI32, I64Const(_), I64Mul, ..] if *l == local => Some(4),
+		_ => None,
+	}
+}
+
+/// Returns the position of the constant amount passed to the call to the gas function at
+/// `offset`, if the call charges a static amount.
+fn charge_start(
+	instructions: &[Instruction],
+	offset: usize,
+	config: &GasInjectionConfig,
+) -> Option<usize> {
+	let mut cursor = offset;
+	if let Some(value) = config.extra_argument() {
+		cursor = cursor.checked_sub(1)?;
+		if instructions[cursor] != Instruction::I32Const(value) {
+			return None;
+		}
+	}
+	cursor = cursor.checked_sub(1)?;
+	match (config.argument_type(), &instructions[cursor]) {
+		(GasArgument::I32, Instruction::I32Const(_)) |
+		(GasArgument::I64, Instruction::I64Const(_)) => Some(cursor),
+		_ => None,
+	}
+}
+
+/// Removes the types at the end of the type section which are not referenced anymore and are
+/// among the `added` ones.
+fn remove_added_types(module: &mut elements::Module, added: &[u32]) {
+	let mut used = Vec::new();
+	if let Some(import_section) = module.import_section() {
+		for entry in import_section.entries() {
+			if let elements::External::Function(type_idx) = entry.external() {
+				used.push(*type_idx);
+			}
+		}
+	}
+	if let Some(function_section) = module.function_section() {
+		used.extend(function_section.entries().iter().map(|func| func.type_ref()));
+	}
+	if let Some(code_section) = module.code_section() {
+		for func_body in code_section.bodies() {
+			for instruction in func_body.code().elements() {
+				if let Instruction::CallIndirect(type_idx, _) = instruction {
+					used.push(*type_idx);
+				}
+			}
+		}
+	}
+
+	if let Some(type_section) = module.type_section_mut() {
+		let types = type_section.types_mut();
+		while let Some(type_idx) = types.len().checked_sub(1).map(|len| len as u32) {
+			if !added.contains(&type_idx) || used.contains(&type_idx) {
+				break;
+			}
+			types.pop();
+		}
+	}
+}
+
+/// Removes the import and type sections if they became empty.
+fn remove_empty_sections(module: &mut elements::Module) {
+	module.sections_mut().retain(|section| match section {
+		elements::Section::Import(import_section) => !import_section.entries().is_empty(),
+		elements::Section::Type(type_section) => !type_section.types().is_empty(),
+		_ => true,
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate wabt;
+
+	use parity_wasm::{elements, serialize};
+	use super::*;
+	use crate::gas::{inject_gas_counter_with_backend, Backend};
+	use crate::rules;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	#[test]
+	fn configured_import_round_trip() {
+		let module = parse_wat(r#"
+(module
+	(import "env" "ext" (func $ext))
+	(memory 1)
+	(table 1 anyfunc)
+	(elem (i32.const 0) $f)
+	(func $f (export "f") (param i32) (result i32)
+		(call $ext)
+		(if (result i32) (get_local 0)
+			(then (memory.grow (get_local 0)))
+			(else (call $f (i32.const 0)))))
+	(start $g)
+	(func $g))
+"#);
+
+		let config = GasInjectionConfig::new("seal0")
+			.with_argument_type(GasArgument::I64)
+			.with_extra_argument(3);
+		let injected = inject_gas_counter_with_backend(
+			module.clone(),
+			&rules::Set::default().with_grow_cost(10),
+			Backend::ImportedFunction(&config),
+		).unwrap();
+		let stripped = strip_gas_counter(injected, &config).unwrap();
+
+		assert_eq!(serialize(stripped).unwrap(), serialize(module).unwrap());
+	}
+
+	struct GrowRules(rules::MemoryGrowCost);
+
+	impl rules::Rules for GrowRules {
+		fn instruction_cost(&self, _: &Instruction) -> Option<u64> {
+			Some(1)
+		}
+
+		fn memory_grow_cost(&self) -> Option<rules::MemoryGrowCost> {
+			Some(self.0.clone())
+		}
+	}
+
+	fn assert_grow_round_trip(cost: rules::MemoryGrowCost, config: &GasInjectionConfig) {
+		let module = parse_wat(r#"
+(module
+	(memory 1)
+	(func (export "f") (param i32) (result i32)
+		(memory.grow (get_local 0))))
+"#);
+
+		let injected = inject_gas_counter_with_backend(
+			module.clone(),
+			&GrowRules(cost),
+			Backend::ImportedFunction(config),
+		).unwrap();
+		let stripped = strip_gas_counter(injected, config).unwrap();
+
+		assert_eq!(serialize(stripped).unwrap(), serialize(module).unwrap());
+	}
+
+	#[test]
+	fn grow_cost_models_round_trip() {
+		let nz = |val| crate::std::num::NonZeroU32::new(val).unwrap();
+		let costs = vec![
+			rules::MemoryGrowCost::Linear(nz(10)),
+			rules::MemoryGrowCost::Tiered(Vec::new()),
+			rules::MemoryGrowCost::Tiered(vec![(1, nz(10)), (16, nz(20)), (u32::MAX, nz(30))]),
+			rules::MemoryGrowCost::Capped {
+				max_pages: 16,
+				cost: Box::new(rules::MemoryGrowCost::TotalSize(nz(100))),
+			},
+		];
+		let configs = vec![
+			GasInjectionConfig::new("env"),
+			GasInjectionConfig::new("env").with_argument_type(GasArgument::I64),
+			GasInjectionConfig::new("env").with_extra_argument(7),
+		];
+
+		for cost in &costs {
+			for config in &configs {
+				assert_grow_round_trip(cost.clone(), config);
+			}
+		}
+	}
+
+	#[test]
+	fn original_trailing_type_is_kept() {
+		let module = parse_wat(r#"
+(module
+	(type $t0 (func (param i32) (result i32)))
+	(type $unused (func (param f32)))
+	(memory 1)
+	(func (export "f") (type $t0)
+		(memory.grow (get_local 0))))
+"#);
+
+		let config = GasInjectionConfig::new("env");
+		let injected = inject_gas_counter_with_backend(
+			module.clone(),
+			&rules::Set::default().with_grow_cost(10),
+			Backend::ImportedFunction(&config),
+		).unwrap();
+		let stripped = strip_gas_counter(injected, &config).unwrap();
+
+		assert_eq!(stripped.type_section().unwrap().types().len(), 2);
+		assert_eq!(serialize(stripped).unwrap(), serialize(module).unwrap());
+	}
+
+	#[cfg(feature = "bulk")]
+	#[test]
+	fn bulk_round_trip() {
+		struct FillRules;
+
+		impl rules::Rules for FillRules {
+			fn instruction_cost(&self, _: &Instruction) -> Option<u64> {
+				Some(1)
+			}
+
+			fn memory_grow_cost(&self) -> Option<rules::MemoryGrowCost> {
+				rules::Set::default().with_grow_cost(10).memory_grow_cost()
+			}
+
+			fn bulk_cost(&self, _: &elements::BulkInstruction) -> Option<crate::std::num::NonZeroU32> {
+				crate::std::num::NonZeroU32::new(2)
+			}
+		}
+
+		let mut features = wabt::Features::new();
+		features.enable_bulk_memory();
+		let module: elements::Module = elements::deserialize_buffer(&wabt::wat2wasm_with_features(r#"
+(module
+	(memory 1)
+	(func (export "f") (param i32 i32 i32) (result i32)
+		(memory.fill (get_local 0) (get_local 1) (get_local 2))
+		(memory.grow (get_local 2))))
+"#, features).expect("Failed to wat2wasm")).expect("Failed to deserialize the module");
+
+		let config = GasInjectionConfig::new("env");
+		let injected = inject_gas_counter_with_backend(
+			module.clone(),
+			&FillRules,
+			Backend::ImportedFunction(&config),
+		).unwrap();
+		let stripped = strip_gas_counter(injected, &config).unwrap();
+
+		assert_eq!(serialize(stripped).unwrap(), serialize(module).unwrap());
+	}
+
+	#[test]
+	fn not_instrumented() {
+		let module = parse_wat("(module (func))");
+		let config = GasInjectionConfig::new("env");
+
+		let (_, error) = strip_gas_counter(module, &config).unwrap_err();
+		assert_eq!(error, StripError::NotInstrumented);
+	}
+
+	#[test]
+	fn unrecognized_gas_call() {
+		let module = parse_wat(r#"
+(module
+	(import "env" "gas" (func $gas (param i32)))
+	(func
+		(i32.const 1)
+		(call $gas)
+		(get_global 0)
+		(call $gas))
+	(global i32 (i32.const 0)))
+"#);
+		let config = GasInjectionConfig::new("env");
+
+		let (stripped, error) = strip_gas_counter(module.clone(), &config).unwrap_err();
+		assert_eq!(error, StripError::UnrecognizedGasCall { func_idx: 1, offset: 3 });
+		assert_eq!(stripped, module);
+	}
+
+	#[test]
+	fn lookalike_helper_is_not_stripped() {
+		// Ends like a `memory.grow` helper, but the charge isn't computed from the page count.
+		let module = parse_wat(r#"
+(module
+	(import "env" "gas" (func $gas (param i64)))
+	(memory 1)
+	(global i32 (i32.const 0))
+	(func (param i32) (result i32)
+		(call $gas
+			(i64.mul (i64.extend_u/i32 (get_global 0)) (i64.const 5)))
+		(memory.grow (get_local 0))))
+"#);
+		let config = GasInjectionConfig::new("env").with_argument_type(GasArgument::I64);
+
+		let (stripped, error) = strip_gas_counter(module.clone(), &config).unwrap_err();
+		assert_eq!(error, StripError::UnrecognizedGasCall { func_idx: 1, offset: 4 });
+		assert_eq!(stripped, module);
+	}
+
+	#[test]
+	fn lookalike_signature_is_not_stripped() {
+		// Has the body of a `memory.grow` helper, but not its signature.
+		let module = parse_wat(r#"
+(module
+	(import "env" "gas" (func $gas (param i64)))
+	(memory 1)
+	(func (param i32 i32) (result i32)
+		(call $gas
+			(i64.mul (i64.extend_u/i32 (get_local 0)) (i64.const 5)))
+		(memory.grow (get_local 0))))
+"#);
+		let config = GasInjectionConfig::new("env").with_argument_type(GasArgument::I64);
+
+		let (stripped, error) = strip_gas_counter(module.clone(), &config).unwrap_err();
+		assert_eq!(error, StripError::UnrecognizedGasCall { func_idx: 1, offset: 4 });
+		assert_eq!(stripped, module);
+	}
+}
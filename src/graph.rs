@@ -126,14 +126,15 @@ pub struct Table {
 
 /// Segment location.
 ///
-/// Reserved for future use. Currenty only `Default` variant is supported.
+/// `Passive` and `WithIndex` segments only occur if parity-wasm is compiled with bulk-memory
+/// operations.
 #[derive(Debug)]
 pub enum SegmentLocation {
-	/// Not used currently.
+	/// Passive segment which is only copied by `memory.init` or `table.init`.
 	Passive,
 	/// Default segment location with index `0`.
 	Default(Vec<Instruction>),
-	/// Not used currently.
+	/// Segment location with a memory or table index other than `0`.
 	WithIndex(u32, Vec<Instruction>),
 }
 
@@ -235,6 +236,24 @@ impl Module {
 		}).collect()
 	}
 
+	fn map_location(&self, index: u32, offset: &Option<elements::InitExpr>) -> SegmentLocation {
+		match offset {
+			None => SegmentLocation::Passive,
+			Some(init_expr) if index == 0 => SegmentLocation::Default(self.map_instructions(init_expr.code())),
+			Some(init_expr) => SegmentLocation::WithIndex(index, self.map_instructions(init_expr.code())),
+		}
+	}
+
+	fn generate_location(&self, location: &SegmentLocation) -> (u32, Option<elements::InitExpr>) {
+		match location {
+			SegmentLocation::Passive => (0, None),
+			SegmentLocation::Default(offset_expr) =>
+				(0, Some(elements::InitExpr::new(self.generate_instructions(&offset_expr[..])))),
+			SegmentLocation::WithIndex(index, offset_expr) =>
+				(*index, Some(elements::InitExpr::new(self.generate_instructions(&offset_expr[..])))),
+		}
+	}
+
 	/// Initialize module from parity-wasm `Module`.
 	pub fn from_elements(module: &elements::Module) -> Result<Self, Error> {
 
@@ -342,22 +361,7 @@ impl Module {
 				},
 				elements::Section::Element(element_section) => {
 					for element_segment in element_section.entries() {
-
-						// let location = if element_segment.passive() {
-						// 	SegmentLocation::Passive
-						// } else if element_segment.index() == 0 {
-						// 	SegmentLocation::Default(Vec::new())
-						// } else {
-						// 	SegmentLocation::WithIndex(element_segment.index(), Vec::new())
-						// };
-
-						// TODO: update parity-wasm and uncomment the above instead
-						let init_expr = element_segment
-							.offset()
-							.as_ref()
-							.expect("parity-wasm is compiled without bulk-memory operations")
-							.code();
-						let location = SegmentLocation::Default(res.map_instructions(init_expr));
+						let location = res.map_location(element_segment.index(), element_segment.offset());
 
 						let funcs_map = element_segment
 							.members().iter()
@@ -385,14 +389,7 @@ impl Module {
 				},
 				elements::Section::Data(data_section) => {
 					for data_segment in data_section.entries() {
-						// TODO: update parity-wasm and use the same logic as in
-						// commented element segment branch
-						let init_expr = data_segment
-							.offset()
-							.as_ref()
-							.expect("parity-wasm is compiled without bulk-memory operations")
-							.code();
-						let location = SegmentLocation::Default(res.map_instructions(init_expr));
+						let location = res.map_location(data_segment.index(), data_segment.offset());
 
 						res.data.push(DataSegment {
 							value: data_segment.value().to_vec(),
@@ -664,23 +661,17 @@ impl Module {
 				let element_segments = element_section.entries_mut();
 
 				for element in self.elements.iter() {
-					match &element.location {
-						SegmentLocation::Default(offset_expr) => {
-							let mut elements_map = Vec::new();
-							for f in element.value.iter() {
-								elements_map.push(f.order().ok_or(Error::DetachedEntry)? as u32);
-							}
-
-							element_segments.push(
-								elements::ElementSegment::new(
-									0,
-									Some(elements::InitExpr::new(self.generate_instructions(&offset_expr[..]))),
-									elements_map,
-								)
-							);
-						},
-						_ => unreachable!("Other segment location types are never added"),
+					let mut elements_map = Vec::new();
+					for f in element.value.iter() {
+						elements_map.push(f.order().ok_or(Error::DetachedEntry)? as u32);
 					}
+
+					let (index, offset) = self.generate_location(&element.location);
+					#[cfg_attr(not(feature = "bulk"), allow(unused_mut))]
+					let mut segment = elements::ElementSegment::new(index, offset, elements_map);
+					#[cfg(feature = "bulk")]
+					segment.set_passive(segment.offset().is_none());
+					element_segments.push(segment);
 				}
 			}
 
@@ -722,18 +713,12 @@ impl Module {
 				let data_segments = data_section.entries_mut();
 
 				for data_entry in self.data.iter() {
-					match &data_entry.location {
-						SegmentLocation::Default(offset_expr) => {
-							data_segments.push(
-								elements::DataSegment::new(
-									0,
-									Some(elements::InitExpr::new(self.generate_instructions(&offset_expr[..]))),
-									data_entry.value.clone(),
-								)
-							);
-						},
-						_ => unreachable!("Other segment location types are never added"),
-					}
+					let (index, offset) = self.generate_location(&data_entry.location);
+					#[cfg_attr(not(feature = "bulk"), allow(unused_mut))]
+					let mut segment = elements::DataSegment::new(index, offset, data_entry.value.clone());
+					#[cfg(feature = "bulk")]
+					segment.set_passive(segment.offset().is_none());
+					data_segments.push(segment);
 				}
 			}
 
@@ -977,4 +962,32 @@ mod tests {
 			"Call should be recalculated to 1"
 		);
 	}
+
+	#[cfg(feature = "bulk")]
+	#[test]
+	fn passive_segments() {
+		let mut module: elements::Module = parity_wasm::deserialize_buffer(
+			&wabt::wat2wasm("(module (table 1 anyfunc) (memory 1) (func))").expect("faled to parse wat!")
+		).expect("Failed to deserialize the module");
+
+		let mut element_segment = elements::ElementSegment::new(0, None, vec![0]);
+		element_segment.set_passive(true);
+		let mut data_segment = elements::DataSegment::new(0, None, vec![1, 2, 3]);
+		data_segment.set_passive(true);
+		let code_idx = module.sections().iter()
+			.position(|section| matches!(section, elements::Section::Code(_)))
+			.expect("Module has a code section");
+		module.sections_mut().insert(code_idx, elements::Section::Element(
+			elements::ElementSection::with_entries(vec![element_segment])
+		));
+		module.sections_mut().push(elements::Section::Data(
+			elements::DataSection::with_entries(vec![data_segment])
+		));
+		let binary = parity_wasm::serialize(module).expect("Failed to serialize the module");
+
+		let sample = super::parse(&binary).expect("error making representation");
+		assert!(matches!(sample.elements[0].location, super::SegmentLocation::Passive));
+		assert!(matches!(sample.data[0].location, super::SegmentLocation::Passive));
+		assert_eq!(super::generate(&sample).expect("Failed to generate binary"), binary);
+	}
 }
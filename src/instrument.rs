@@ -0,0 +1,192 @@
+//! Instrumentation with both gas metering and the stack height limiter.
+
+use crate::std::fmt;
+
+use parity_wasm::elements::{self, ValueType};
+use crate::gas::{self, Backend, GasInjectionConfig};
+use crate::rules::Rules;
+use crate::stack_height::{self, LimiterConfig, StackCostModel};
+
+/// Error of the combined instrumentation.
+#[derive(Debug)]
+pub enum Error {
+	/// The stack height limiter couldn't be injected.
+	StackHeight(stack_height::Error),
+	/// The gas metering couldn't be injected.
+	///
+	/// Function indices refer to the module instrumented with the stack height limiter, in which
+	/// the functions of the original module keep their indices.
+	Gas(gas::Error),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			Error::StackHeight(error) => write!(f, "Stack height limiter: {}", error),
+			Error::Gas(error) => write!(f, "Gas metering: {}", error),
+		}
+	}
+}
+
+/// Instruments a module with gas metering charged by the "gas" function imported from
+/// `gas_module_name` and a stack height limiter trapping once the stack height exceeds
+/// `stack_limit`.
+///
+/// See `instrument_with_config` for the details.
+pub fn instrument<R: Rules>(
+	module: elements::Module,
+	rules: &R,
+	gas_module_name: &str,
+	stack_limit: u32,
+) -> Result<elements::Module, Error> {
+	instrument_with_config(
+		module,
+		rules,
+		Backend::ImportedFunction(&GasInjectionConfig::new(gas_module_name)),
+		&LimiterConfig::new(stack_limit),
+	)
+}
+
+/// Instruments a module with gas metering using `backend` and the stack height limiter described
+/// by `config`.
+///
+/// The stack height limiter is injected first, so the thunks and lookup functions it generates
+/// as well as the code it adds around calls are metered like the rest of the module. Afterwards
+/// the gas metering rewrites all function indices, including the ones referring to the generated
+/// functions, and adds its helpers charging for `memory.grow` and bulk memory operations. The
+/// helpers aren't metered themselves, their static cost is charged along with the instruction
+/// each of them replaces.
+///
+/// The stack costs of the limiter include the values the gas metering pushes onto the value stack
+/// and the frames of its helpers, see `LimiterConfig::with_extra_stack_cost`. The cost of the
+/// metering is added to every function: the arguments of a charge, which is placed on top of the
+/// values a metered block starts with, plus the highest stack cost of the helpers, if there are
+/// any. The helpers never recurse, the only function they call is the imported gas function of
+/// the `ImportedFunction` backend, which runs outside of the module's stack.
+pub fn instrument_with_config<R: Rules>(
+	module: elements::Module,
+	rules: &R,
+	backend: Backend,
+	config: &LimiterConfig,
+) -> Result<elements::Module, Error> {
+	let gas_stack_cost = gas_stack_cost(&module, rules, backend, config.cost_model())?;
+	let extra_stack_cost = config.extra_stack_cost().saturating_add(gas_stack_cost);
+	let config = config.with_extra_stack_cost(extra_stack_cost);
+	let module = stack_height::inject_limiter_with_config(module, &config)
+		.map_err(Error::StackHeight)?;
+	gas::inject_gas_counter_with_backend(module, rules, backend)
+		.map_err(|(_, error)| Error::Gas(error))
+}
+
+/// Returns the stack cost the gas metering by `backend` adds to every function of `module`.
+fn gas_stack_cost<R: Rules>(
+	module: &elements::Module,
+	rules: &R,
+	backend: Backend,
+	model: StackCostModel,
+) -> Result<u32, Error> {
+	let sizes = model.value_sizes();
+	let charge_cost: u32 = match backend {
+		Backend::ImportedFunction(config) => config.params().map(|param| sizes.size_of(param)).sum(),
+		// The remaining gas is compared with and decreased by the cost.
+		Backend::MutableGlobal(_) => 2 * sizes.size_of(ValueType::I64),
+	};
+
+	// The helpers are the functions the metering adds after all others.
+	let metered = gas::inject_gas_counter_with_backend(module.clone(), rules, backend)
+		.map_err(|(_, error)| Error::Gas(error))?;
+	let defined_funcs = |module: &elements::Module| {
+		module.functions_space() - module.import_count(elements::ImportCountType::Function)
+	};
+	let helpers = defined_funcs(&metered) - defined_funcs(module);
+	let stack_costs = stack_height::compute_stack_costs(&metered, model)
+		.map_err(Error::StackHeight)?;
+	let helper_cost = stack_costs[stack_costs.len() - helpers..].iter().max().cloned().unwrap_or(0);
+
+	Ok(charge_cost.saturating_add(helper_cost))
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate wabt;
+
+	use parity_wasm::elements::Instruction::*;
+	use super::*;
+	use crate::gas::GasArgument;
+	use crate::rules;
+	use crate::stack_height::ValueSizes;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	const SOURCE: &str = r#"
+(module
+	(memory 1)
+	(func (export "f") (param i32) (result i32)
+		(memory.grow (get_local 0))))
+"#;
+
+	#[test]
+	fn charge_stack_cost() {
+		let module = parse_wat(SOURCE);
+		let rules = rules::Set::default();
+
+		let config = GasInjectionConfig::new("env");
+		let cost = gas_stack_cost(
+			&module,
+			&rules,
+			Backend::ImportedFunction(&config),
+			StackCostModel::LocalDeclarations,
+		).unwrap();
+		assert_eq!(cost, 1);
+
+		// An i64 amount and an i32 extra argument.
+		let config = GasInjectionConfig::new("env")
+			.with_argument_type(GasArgument::I64)
+			.with_extra_argument(7);
+		let cost = gas_stack_cost(
+			&module,
+			&rules,
+			Backend::ImportedFunction(&config),
+			StackCostModel::Sized(ValueSizes::bytes()),
+		).unwrap();
+		assert_eq!(cost, 8 + 4);
+	}
+
+	#[test]
+	fn helper_stack_cost() {
+		let module = parse_wat(SOURCE);
+		let rules = rules::Set::default().with_grow_cost(10);
+
+		// The helper declares an i64 local and has at most two i64 values on its stack.
+		let cost = gas_stack_cost(
+			&module,
+			&rules,
+			Backend::MutableGlobal("gas_left"),
+			StackCostModel::LocalDeclarations,
+		).unwrap();
+		assert_eq!(cost, 2 + 3);
+
+		let config = GasInjectionConfig::new("env");
+		let module = instrument_with_config(
+			module,
+			&rules,
+			Backend::ImportedFunction(&config),
+			&LimiterConfig::new(1024),
+		).unwrap();
+		// The export is charged its own stack cost of 1, the amount of a charge and the helper.
+		let thunk = &module.code_section().unwrap().bodies()[1];
+		let stack_costs = thunk
+			.code()
+			.elements()
+			.windows(3)
+			.filter_map(|window| match window {
+				[GetGlobal(_), I32Const(cost), I32Add] => Some(*cost),
+				_ => None,
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(stack_costs, vec![1 + 1 + 3]);
+	}
+}
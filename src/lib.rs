@@ -16,9 +16,10 @@ pub mod rules;
 
 mod build;
 mod ext;
-mod gas;
+mod instrument;
 mod optimizer;
 mod pack;
+mod remap;
 mod runtime_type;
 mod graph;
 mod ref_list;
@@ -28,15 +29,21 @@ mod export_globals;
 #[cfg(feature = "cli")]
 pub mod logger;
 
+pub mod gas;
 pub mod stack_height;
 
 pub use build::{build, Error as BuildError, SourceTarget};
 pub use ext::{
 	externalize, externalize_mem, shrink_unknown_stack, underscore_funcs, ununderscore_funcs,
 };
-pub use gas::inject_gas_counter;
+pub use gas::{
+	inject_gas_counter, inject_gas_counter_with_backend, strip_gas_counter, Backend as GasBackend,
+	Error as GasError, GasArgument, GasInjectionConfig,
+};
+pub use instrument::{instrument, instrument_with_config, Error as InstrumentError};
 pub use optimizer::{optimize, Error as OptimizerError};
 pub use pack::{pack_instance, Error as PackingError};
+pub use remap::{IndexMapping, IndexRemap};
 pub use runtime_type::inject_runtime_type;
 pub use graph::{Module, parse as graph_parse, generate as graph_generate};
 pub use ref_list::{RefList, Entry, EntryRef, DeleteTransaction};
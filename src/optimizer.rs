@@ -7,6 +7,7 @@ use crate::std::mem;
 
 use parity_wasm::elements;
 
+use crate::remap::{IndexMapping, IndexRemap};
 use crate::symbols::{Symbol, expand_symbols, push_code_symbols, resolve_function};
 
 #[derive(Debug)]
@@ -46,28 +47,17 @@ pub fn optimize(
 	let mut init_symbols = Vec::new();
 	if let Some(data_section) = module.data_section() {
 		for segment in data_section.entries() {
-			push_code_symbols(
-				&module,
-				segment
-					.offset()
-					.as_ref()
-					.expect("parity-wasm is compiled without bulk-memory operations")
-					.code(),
-				&mut init_symbols,
-			);
+			// Passive segments don't have an offset expression.
+			if let Some(offset) = segment.offset() {
+				push_code_symbols(&module, offset.code(), &mut init_symbols);
+			}
 		}
 	}
 	if let Some(elements_section) = module.elements_section() {
 		for segment in elements_section.entries() {
-			push_code_symbols(
-				&module,
-				segment
-					.offset()
-					.as_ref()
-					.expect("parity-wasm is compiled without bulk-memory operations")
-					.code(),
-				&mut init_symbols
-			);
+			if let Some(offset) = segment.offset() {
+				push_code_symbols(&module, offset.code(), &mut init_symbols);
+			}
 			for func_index in segment.members() {
 				stay.insert(resolve_function(&module, *func_index));
 			}
@@ -210,168 +200,18 @@ pub fn optimize(
 		}
 	}
 
-	if !eliminated_globals.is_empty() || !eliminated_funcs.is_empty() || !eliminated_types.is_empty() {
-		// Finaly, rewire all calls, globals references and types to the new indices
-		//   (only if there is anything to do)
-		// When sorting primitives sorting unstable is faster without any difference in result.
-		eliminated_globals.sort_unstable();
-		eliminated_funcs.sort_unstable();
-		eliminated_types.sort_unstable();
-
-		for section in module.sections_mut() {
-			match section {
-				elements::Section::Start(func_index) if !eliminated_funcs.is_empty() => {
-					let totalle = eliminated_funcs.iter().take_while(|i| (**i as u32) < *func_index).count();
-					*func_index -= totalle as u32;
-				},
-				elements::Section::Function(function_section) if !eliminated_types.is_empty() => {
-					for func_signature in function_section.entries_mut() {
-						let totalle = eliminated_types.iter().take_while(|i| (**i as u32) < func_signature.type_ref()).count();
-						*func_signature.type_ref_mut() -= totalle as u32;
-					}
-				},
-				elements::Section::Import(import_section) if !eliminated_types.is_empty() => {
-					for import_entry in import_section.entries_mut() {
-						if let elements::External::Function(type_ref) = import_entry.external_mut() {
-							let totalle = eliminated_types.iter().take_while(|i| (**i as u32) < *type_ref).count();
-							*type_ref -= totalle as u32;
-						}
-					}
-				},
-				elements::Section::Code(code_section) if !eliminated_globals.is_empty() || !eliminated_funcs.is_empty() => {
-					for func_body in code_section.bodies_mut() {
-						if !eliminated_funcs.is_empty() {
-							update_call_index(func_body.code_mut(), &eliminated_funcs);
-						}
-						if !eliminated_globals.is_empty() {
-							update_global_index(func_body.code_mut().elements_mut(), &eliminated_globals)
-						}
-						if !eliminated_types.is_empty() {
-							update_type_index(func_body.code_mut(), &eliminated_types)
-						}
-					}
-				},
-				elements::Section::Export(export_section) => {
-					for export in export_section.entries_mut() {
-						match export.internal_mut() {
-							elements::Internal::Function(func_index) => {
-								let totalle = eliminated_funcs.iter().take_while(|i| (**i as u32) < *func_index).count();
-								*func_index -= totalle as u32;
-							},
-							elements::Internal::Global(global_index) => {
-								let totalle = eliminated_globals.iter().take_while(|i| (**i as u32) < *global_index).count();
-								*global_index -= totalle as u32;
-							},
-							_ => {}
-						}
-					}
-				},
-				elements::Section::Global(global_section) => {
-					for global_entry in global_section.entries_mut() {
-						update_global_index(global_entry.init_expr_mut().code_mut(), &eliminated_globals)
-					}
-				},
-				elements::Section::Data(data_section) => {
-					for segment in data_section.entries_mut() {
-						update_global_index(
-							segment
-								.offset_mut()
-								.as_mut()
-								.expect("parity-wasm is compiled without bulk-memory operations")
-								.code_mut(),
-							&eliminated_globals,
-						)
-					}
-				},
-				elements::Section::Element(elements_section) => {
-					for segment in elements_section.entries_mut() {
-						update_global_index(
-							segment
-							.offset_mut()
-							.as_mut()
-							.expect("parity-wasm is compiled without bulk-memory operations")
-							.code_mut(),
-							&eliminated_globals
-						);
-						// update all indirect call addresses initial values
-						for func_index in segment.members_mut() {
-							let totalle = eliminated_funcs.iter().take_while(|i| (**i as u32) < *func_index).count();
-							*func_index -= totalle as u32;
-						}
-					}
-				},
-				elements::Section::Name(name_section) => {
-					if let Some(func_name) = name_section.functions_mut() {
-						let mut func_name_map = mem::take(func_name.names_mut());
-						for index in &eliminated_funcs {
-							func_name_map.remove(*index as u32);
-						}
-						let updated_map = func_name_map.into_iter().map(|(index, value)| {
-							let totalle = eliminated_funcs.iter().take_while(|i| (**i as u32) < index).count() as u32;
-							(index - totalle, value)
-						}).collect();
-						*func_name.names_mut() = updated_map;
-					}
-
-					if let Some(local_name) = name_section.locals_mut() {
-						let mut local_names_map = mem::take(local_name.local_names_mut());
-						for index in &eliminated_funcs {
-							local_names_map.remove(*index as u32);
-						}
-						let updated_map = local_names_map.into_iter().map(|(index, value)| {
-							let totalle = eliminated_funcs.iter().take_while(|i| (**i as u32) < index).count() as u32;
-							(index - totalle, value)
-						}).collect();
-						*local_name.local_names_mut() = updated_map;
-					}
-				}
-				_ => { }
-			}
-		}
-	}
+	// Finaly, rewire all references to functions, globals and types to the new indices
+	let removed = |indices: Vec<usize>| IndexMapping::removed(indices.into_iter().map(|index| index as u32).collect());
+	IndexRemap::new()
+		.with_functions(removed(eliminated_funcs))
+		.with_globals(removed(eliminated_globals))
+		.with_types(removed(eliminated_types))
+		.apply(module);
 
 	Ok(())
 }
 
 
-pub fn update_call_index(instructions: &mut elements::Instructions, eliminated_indices: &[usize]) {
-	use parity_wasm::elements::Instruction::*;
-	for instruction in instructions.elements_mut().iter_mut() {
-		if let Call(call_index) = instruction {
-			let totalle = eliminated_indices.iter().take_while(|i| (**i as u32) < *call_index).count();
-			trace!("rewired call {} -> call {}", *call_index, *call_index - totalle as u32);
-			*call_index -= totalle as u32;
-		}
-	}
-}
-
-/// Updates global references considering the _ordered_ list of eliminated indices
-pub fn update_global_index(instructions: &mut Vec<elements::Instruction>, eliminated_indices: &[usize]) {
-	use parity_wasm::elements::Instruction::*;
-	for instruction in instructions.iter_mut() {
-		match instruction {
-			GetGlobal(index) | SetGlobal(index) => {
-				let totalle = eliminated_indices.iter().take_while(|i| (**i as u32) < *index).count();
-				trace!("rewired global {} -> global {}", *index, *index - totalle as u32);
-				*index -= totalle as u32;
-			},
-			_ => { },
-		}
-	}
-}
-
-/// Updates global references considering the _ordered_ list of eliminated indices
-pub fn update_type_index(instructions: &mut elements::Instructions, eliminated_indices: &[usize]) {
-	use parity_wasm::elements::Instruction::*;
-	for instruction in instructions.elements_mut().iter_mut() {
-		if let CallIndirect(call_index, _) = instruction {
-			let totalle = eliminated_indices.iter().take_while(|i| (**i as u32) < *call_index).count();
-			trace!("rewired call_indrect {} -> call_indirect {}", *call_index, *call_index - totalle as u32);
-			*call_index -= totalle as u32;
-		}
-	}
-}
-
 pub fn import_section(module: &mut elements::Module) -> Option<&mut elements::ImportSection> {
    for section in module.sections_mut() {
 		if let elements::Section::Import(sect) = section {
@@ -8,7 +8,7 @@ use parity_wasm::elements::{
 };
 use parity_wasm::builder;
 use super::TargetRuntime;
-use super::gas::update_call_index;
+use super::remap::{IndexMapping, IndexRemap};
 
 /// Pack error.
 ///
@@ -111,31 +111,9 @@ pub fn pack_instance(raw_module: Vec<u8>, mut ctor_module: elements::Module, tar
 
 			let ret_func = ctor_module.import_count(ImportCountType::Function) as u32 - 1;
 
-			for section in ctor_module.sections_mut() {
-				match section {
-					elements::Section::Code(code_section) => {
-						for func_body in code_section.bodies_mut() {
-							update_call_index(func_body.code_mut(), ret_func);
-						}
-					},
-					elements::Section::Export(export_section) => {
-						for export in export_section.entries_mut() {
-							if let elements::Internal::Function(func_index) = export.internal_mut() {
-								if *func_index >= ret_func { *func_index += 1}
-							}
-						}
-					},
-					elements::Section::Element(elements_section) => {
-						for segment in elements_section.entries_mut() {
-							// update all indirect call addresses initial values
-							for func_index in segment.members_mut() {
-								if *func_index >= ret_func { *func_index += 1}
-							}
-						}
-					},
-					_ => { }
-				}
-			}
+			IndexRemap::new()
+				.with_functions(IndexMapping::Inserted { index: ret_func, count: 1 })
+				.apply(&mut ctor_module);
 
 			create_func_id += 1;
 			ret_func
@@ -161,12 +139,12 @@ pub fn pack_instance(raw_module: Vec<u8>, mut ctor_module: elements::Module, tar
 
 	for section in ctor_module.sections_mut() {
 		if let Section::Data(data_section) = section {
-			let (index, offset) = if let Some(entry) = data_section.entries().iter().last() {
-				let init_expr = entry
-					.offset()
-					.as_ref()
-					.expect("parity-wasm is compiled without bulk-memory operations")
-					.code();
+			// Passive segments are not placed in memory, so they don't occupy any address.
+			let last_active = data_section.entries()
+				.iter()
+				.rev()
+				.find_map(|entry| entry.offset().as_ref().map(|init_expr| (entry, init_expr.code())));
+			let (index, offset) = if let Some((entry, init_expr)) = last_active {
 				if let Instruction::I32Const(offst) = init_expr[0] {
 					let len = entry.value().len() as i32;
 					let offst = offst as i32;
@@ -187,6 +165,13 @@ pub fn pack_instance(raw_module: Vec<u8>, mut ctor_module: elements::Module, tar
 		}
 	}
 
+	// The data count section has to match the number of data segments
+	for section in ctor_module.sections_mut() {
+		if let Section::DataCount(count) = section {
+			*count += 1;
+		}
+	}
+
 	let mut new_module = builder::from_module(ctor_module)
 		.function()
 		.signature().build()
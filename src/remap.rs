@@ -0,0 +1,381 @@
+//! Rewriting of all references to functions, globals, types, tables and memories of a module
+//! after entries of their index spaces were inserted, removed or reordered.
+
+use crate::std::mem;
+use crate::std::vec::Vec;
+
+use parity_wasm::elements::{self, Instruction};
+
+/// Maps the old indices of an index space to the new ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum IndexMapping {
+	/// All indices stay the same.
+	#[default]
+	Identity,
+	/// `count` entries were inserted at `index`, so all indices greater or equal to `index` are
+	/// shifted up by `count`.
+	Inserted {
+		index: u32,
+		count: u32,
+	},
+	/// The entries at the indices, which are sorted and unique, were removed. All other indices
+	/// are shifted down by the number of removed indices below them.
+	///
+	/// References to removed entries are left untouched, names of removed functions are dropped.
+	/// Use `IndexMapping::removed` to construct it from arbitrary indices.
+	Removed(Vec<u32>),
+	/// The entry at index `i` moved to index `new_indices[i]`. Indices out of the bounds of
+	/// `new_indices` stay the same.
+	Explicit(Vec<u32>),
+}
+
+impl IndexMapping {
+	/// The entries at `indices` were removed, in any order and possibly repeated.
+	pub fn removed(mut indices: Vec<u32>) -> Self {
+		indices.sort_unstable();
+		indices.dedup();
+		IndexMapping::Removed(indices)
+	}
+
+	/// Returns the new index of the entry at `index`, or `None` if the entry was removed.
+	pub fn map(&self, index: u32) -> Option<u32> {
+		match self {
+			IndexMapping::Identity => Some(index),
+			IndexMapping::Inserted { index: inserted, count } => {
+				Some(if index >= *inserted { index + count } else { index })
+			},
+			IndexMapping::Removed(removed) => match removed.binary_search(&index) {
+				Ok(_) => None,
+				Err(removed_before) => Some(index - removed_before as u32),
+			},
+			IndexMapping::Explicit(new_indices) => {
+				Some(new_indices.get(index as usize).cloned().unwrap_or(index))
+			},
+		}
+	}
+
+	fn update(&self, index: &mut u32) {
+		if let Some(new_index) = self.map(*index) {
+			*index = new_index;
+		}
+	}
+
+	fn update_u8(&self, index: &mut u8) {
+		if let Some(new_index) = self.map(*index as u32) {
+			*index = new_index as u8;
+		}
+	}
+
+	fn is_identity(&self) -> bool {
+		match self {
+			IndexMapping::Identity => true,
+			IndexMapping::Inserted { count, .. } => *count == 0,
+			IndexMapping::Removed(removed) => removed.is_empty(),
+			IndexMapping::Explicit(new_indices) => {
+				new_indices.iter().enumerate().all(|(index, new_index)| index as u32 == *new_index)
+			},
+		}
+	}
+}
+
+/// Mappings of the index spaces of a module, applied to every reference to them.
+///
+/// The references are updated in the code, the function and import type references, exports,
+/// the start function, element and data segments including their offsets, the initializer
+/// expressions of globals and the function and local names of the name section. The entries
+/// defining the index spaces themselves, e.g. imports and function bodies, have to be inserted,
+/// removed or reordered by the caller.
+///
+/// ```
+/// use pwasm_utils::{IndexMapping, IndexRemap};
+/// # let mut module = parity_wasm::builder::module().build();
+///
+/// // A function import was added after the first two function imports.
+/// IndexRemap::new()
+///     .with_functions(IndexMapping::Inserted { index: 2, count: 1 })
+///     .apply(&mut module);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IndexRemap {
+	functions: IndexMapping,
+	globals: IndexMapping,
+	types: IndexMapping,
+	tables: IndexMapping,
+	memories: IndexMapping,
+}
+
+impl IndexRemap {
+	/// Keeps all indices the same.
+	pub fn new() -> Self {
+		IndexRemap::default()
+	}
+
+	/// Sets the mapping of the function index space.
+	pub fn with_functions(mut self, mapping: IndexMapping) -> Self {
+		self.functions = mapping;
+		self
+	}
+
+	/// Sets the mapping of the global index space.
+	pub fn with_globals(mut self, mapping: IndexMapping) -> Self {
+		self.globals = mapping;
+		self
+	}
+
+	/// Sets the mapping of the type index space.
+	pub fn with_types(mut self, mapping: IndexMapping) -> Self {
+		self.types = mapping;
+		self
+	}
+
+	/// Sets the mapping of the table index space.
+	pub fn with_tables(mut self, mapping: IndexMapping) -> Self {
+		self.tables = mapping;
+		self
+	}
+
+	/// Sets the mapping of the memory index space.
+	pub fn with_memories(mut self, mapping: IndexMapping) -> Self {
+		self.memories = mapping;
+		self
+	}
+
+	/// Updates all references in `module`.
+	pub fn apply(&self, module: &mut elements::Module) {
+		for section in module.sections_mut() {
+			match section {
+				elements::Section::Import(import_section) => {
+					for entry in import_section.entries_mut() {
+						if let elements::External::Function(type_ref) = entry.external_mut() {
+							self.types.update(type_ref);
+						}
+					}
+				},
+				elements::Section::Function(function_section) => {
+					for func in function_section.entries_mut() {
+						self.types.update(func.type_ref_mut());
+					}
+				},
+				elements::Section::Code(code_section) => {
+					for func_body in code_section.bodies_mut() {
+						self.apply_instructions(func_body.code_mut().elements_mut());
+					}
+				},
+				elements::Section::Export(export_section) => {
+					for entry in export_section.entries_mut() {
+						match entry.internal_mut() {
+							elements::Internal::Function(index) => self.functions.update(index),
+							elements::Internal::Global(index) => self.globals.update(index),
+							elements::Internal::Table(index) => self.tables.update(index),
+							elements::Internal::Memory(index) => self.memories.update(index),
+						}
+					}
+				},
+				elements::Section::Global(global_section) => {
+					for entry in global_section.entries_mut() {
+						self.apply_instructions(entry.init_expr_mut().code_mut());
+					}
+				},
+				elements::Section::Start(func_index) => self.functions.update(func_index),
+				elements::Section::Element(element_section) => {
+					for segment in element_section.entries_mut() {
+						if let Some(offset) = segment.offset_mut() {
+							self.apply_instructions(offset.code_mut());
+						}
+						for func_index in segment.members_mut() {
+							self.functions.update(func_index);
+						}
+						let mut table_index = segment.index();
+						self.tables.update(&mut table_index);
+						if table_index != segment.index() {
+							#[cfg_attr(not(feature = "bulk"), allow(unused_mut))]
+							let mut new_segment = elements::ElementSegment::new(
+								table_index,
+								segment.offset().clone(),
+								mem::take(segment.members_mut()),
+							);
+							#[cfg(feature = "bulk")]
+							new_segment.set_passive(segment.passive());
+							*segment = new_segment;
+						}
+					}
+				},
+				elements::Section::Data(data_section) => {
+					for segment in data_section.entries_mut() {
+						if let Some(offset) = segment.offset_mut() {
+							self.apply_instructions(offset.code_mut());
+						}
+						let mut memory_index = segment.index();
+						self.memories.update(&mut memory_index);
+						if memory_index != segment.index() {
+							#[cfg_attr(not(feature = "bulk"), allow(unused_mut))]
+							let mut new_segment = elements::DataSegment::new(
+								memory_index,
+								segment.offset().clone(),
+								mem::take(segment.value_mut()),
+							);
+							#[cfg(feature = "bulk")]
+							new_segment.set_passive(segment.passive());
+							*segment = new_segment;
+						}
+					}
+				},
+				elements::Section::Name(name_section) if !self.functions.is_identity() => {
+					if let Some(function_names) = name_section.functions_mut() {
+						let names = mem::take(function_names.names_mut());
+						*function_names.names_mut() = names
+							.into_iter()
+							.filter_map(|(index, name)| Some((self.functions.map(index)?, name)))
+							.collect();
+					}
+					if let Some(local_names) = name_section.locals_mut() {
+						let names = mem::take(local_names.local_names_mut());
+						*local_names.local_names_mut() = names
+							.into_iter()
+							.filter_map(|(index, names)| Some((self.functions.map(index)?, names)))
+							.collect();
+					}
+				},
+				_ => {},
+			}
+		}
+	}
+
+	/// Updates all references in `instructions`, which are a function body or an initializer
+	/// expression.
+	pub fn apply_instructions(&self, instructions: &mut [Instruction]) {
+		for instruction in instructions {
+			match instruction {
+				Instruction::Call(index) => self.functions.update(index),
+				Instruction::CallIndirect(type_index, table_index) => {
+					self.types.update(type_index);
+					self.tables.update_u8(table_index);
+				},
+				Instruction::GetGlobal(index) | Instruction::SetGlobal(index) => {
+					self.globals.update(index)
+				},
+				Instruction::CurrentMemory(index) | Instruction::GrowMemory(index) => {
+					self.memories.update_u8(index)
+				},
+				_ => {},
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate wabt;
+
+	use parity_wasm::elements;
+	use super::*;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	#[test]
+	fn mappings() {
+		assert_eq!(IndexMapping::Identity.map(3), Some(3));
+
+		let inserted = IndexMapping::Inserted { index: 2, count: 3 };
+		assert_eq!(inserted.map(1), Some(1));
+		assert_eq!(inserted.map(2), Some(5));
+
+		let removed = IndexMapping::removed(vec![4, 1, 4]);
+		assert_eq!(removed, IndexMapping::Removed(vec![1, 4]));
+		assert_eq!(removed.map(0), Some(0));
+		assert_eq!(removed.map(1), None);
+		assert_eq!(removed.map(3), Some(2));
+		assert_eq!(removed.map(5), Some(3));
+
+		let explicit = IndexMapping::Explicit(vec![1, 0]);
+		assert_eq!(explicit.map(0), Some(1));
+		assert_eq!(explicit.map(2), Some(2));
+	}
+
+	#[test]
+	fn every_reference() {
+		let mut module = parse_wat(r#"
+(module
+	(type (func))
+	(type (func (param i32)))
+	(import "env" "f" (func $f (type 1)))
+	(import "env" "g" (global $g i32))
+	(global $h (mut i32) (get_global $g))
+	(func $a (type 0)
+		get_global $h
+		call $f
+		call $b
+	)
+	(func $b (type 0)
+		i32.const 0
+		call_indirect (type 0)
+	)
+	(table 2 anyfunc)
+	(elem (get_global $g) $a $b)
+	(memory 1)
+	(data (get_global $g) "abc")
+	(export "a" (func $a))
+	(export "h" (global $h))
+	(start $b)
+)
+"#);
+
+		IndexRemap::new()
+			.with_functions(IndexMapping::Inserted { index: 1, count: 2 })
+			.with_globals(IndexMapping::Explicit(vec![1, 0]))
+			.with_types(IndexMapping::Explicit(vec![1, 0]))
+			.apply(&mut module);
+
+		use parity_wasm::elements::Instruction::*;
+		let imports = module.import_section().unwrap().entries();
+		assert_eq!(*imports[0].external(), elements::External::Function(0));
+		let type_refs: Vec<_> = module.function_section().unwrap().entries().iter().map(|f| f.type_ref()).collect();
+		assert_eq!(type_refs, vec![1, 1]);
+		let bodies = module.code_section().unwrap().bodies();
+		assert_eq!(bodies[0].code().elements(), &[GetGlobal(0), Call(0), Call(4), End]);
+		assert_eq!(bodies[1].code().elements(), &[I32Const(0), CallIndirect(1, 0), End]);
+		assert_eq!(module.global_section().unwrap().entries()[0].init_expr().code(), &[GetGlobal(1), End]);
+		let segment = &module.elements_section().unwrap().entries()[0];
+		assert_eq!(segment.members(), &[3, 4]);
+		assert_eq!(segment.offset().as_ref().unwrap().code(), &[GetGlobal(1), End]);
+		let segment = &module.data_section().unwrap().entries()[0];
+		assert_eq!(segment.offset().as_ref().unwrap().code(), &[GetGlobal(1), End]);
+		let exports = module.export_section().unwrap().entries();
+		assert_eq!(*exports[0].internal(), elements::Internal::Function(3));
+		assert_eq!(*exports[1].internal(), elements::Internal::Global(0));
+		assert_eq!(module.start_section(), Some(4));
+	}
+
+	#[test]
+	fn function_names() {
+		let binary = wabt::Wat2Wasm::new()
+			.write_debug_names(true)
+			.convert(r#"
+(module
+	(func $a (param $x i32))
+	(func $b)
+	(func $c (param $y i32))
+)
+"#)
+			.expect("Failed to wat2wasm");
+		let mut module = elements::Module::from_bytes(binary.as_ref())
+			.expect("Failed to deserialize the module")
+			.parse_names()
+			.expect("Failed to parse names");
+
+		IndexRemap::new()
+			.with_functions(IndexMapping::removed(vec![1]))
+			.apply(&mut module);
+
+		let names_section = module.names_section().expect("Names section exists");
+		let function_names = names_section.functions().expect("Function names exist").names();
+		assert_eq!(function_names.get(0).map(|name| name.as_str()), Some("a"));
+		assert_eq!(function_names.get(1).map(|name| name.as_str()), Some("c"));
+		assert_eq!(function_names.get(2), None);
+		let local_names = names_section.locals().expect("Local names exist").local_names();
+		assert_eq!(local_names.get(1).and_then(|names| names.get(0)).map(|name| name.as_str()), Some("y"));
+	}
+}
@@ -3,12 +3,66 @@ use crate::std::collections::HashMap as Map;
 #[cfg(not(features = "std"))]
 use crate::std::collections::BTreeMap as Map;
 
+use crate::std::boxed::Box;
+use crate::std::fmt;
 use crate::std::num::NonZeroU32;
 use crate::std::str::FromStr;
+use crate::std::string::{String, ToString};
+use crate::std::vec::Vec;
 use crate::Instruction;
+#[cfg(feature = "bulk")]
+use parity_wasm::elements::BulkInstruction;
+
+mod immediates;
+mod opcode;
+
+pub use self::immediates::WithImmediateCosts;
+pub use self::opcode::Opcode;
 
 pub struct UnknownInstruction;
 
+/// Error that occured while parsing a textual rule set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+	/// Line (starting at 1) the error was found on.
+	pub line: usize,
+	/// What is wrong with the line.
+	pub kind: ParseErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+	/// The line is neither empty, nor a comment, nor a `key = value` pair.
+	MissingSeparator,
+	/// The key is neither a known instruction class nor a special key.
+	UnknownClass(String),
+	/// The key in the `[opcodes]` section is not a known opcode.
+	UnknownOpcode(String),
+	/// The key in the `[imports]` section is not of the form `module.field`.
+	InvalidImport(String),
+	/// The section header names an unknown section or one that was already specified.
+	UnknownSection(String),
+	/// The value can't be used for the key.
+	InvalidValue(String),
+	/// The key was already specified on an earlier line.
+	DuplicateKey(String),
+}
+
+impl fmt::Display for ParseError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		write!(f, "line {}: ", self.line)?;
+		match self.kind {
+			ParseErrorKind::MissingSeparator => write!(f, "expected `key = value`"),
+			ParseErrorKind::UnknownClass(ref key) => write!(f, "unknown instruction class `{}`", key),
+			ParseErrorKind::UnknownOpcode(ref key) => write!(f, "unknown opcode `{}`", key),
+			ParseErrorKind::InvalidImport(ref key) => write!(f, "expected `module.field`, found `{}`", key),
+			ParseErrorKind::UnknownSection(ref name) => write!(f, "unknown section `{}`", name),
+			ParseErrorKind::InvalidValue(ref value) => write!(f, "invalid value `{}`", value),
+			ParseErrorKind::DuplicateKey(ref key) => write!(f, "`{}` is specified twice", key),
+		}
+	}
+}
+
 /// An interface that describes instruction costs.
 pub trait Rules {
 	/// Returns the cost for the passed `instruction`.
@@ -16,7 +70,7 @@ pub trait Rules {
 	/// Returning `None` makes the gas instrumention end with an error. This is meant
 	/// as a way to have a partial rule set where any instruction that is not specifed
 	/// is considered as forbidden.
-	fn instruction_cost(&self, instruction: &Instruction) -> Option<u32>;
+	fn instruction_cost(&self, instruction: &Instruction) -> Option<u64>;
 
 	/// Returns the costs for growing the memory using the `memory.grow` instruction.
 	///
@@ -27,13 +81,44 @@ pub trait Rules {
 	/// those costs depend on the stack and must be injected as code into the function calling
 	/// `memory.grow`. Therefore returning `Some` comes with a performance cost.
 	fn memory_grow_cost(&self) -> Option<MemoryGrowCost>;
+
+	/// Returns the cost of calling the imported function `field` of the module `module`.
+	///
+	/// The cost is charged in addition to the one of the `call` instruction as part of the
+	/// metered block containing the call. Defaults to no additional charge.
+	fn import_call_cost(&self, _module: &str, _field: &str) -> u64 {
+		0
+	}
+
+	/// Returns the cost per byte or table element the bulk `instruction` operates on.
+	///
+	/// Like the costs for `memory.grow` these are charged in addition to `instruction_cost` by
+	/// a helper function injected in place of the instruction, as the amount is only known at
+	/// runtime. `memory.copy`, `memory.fill`, `memory.init`, `table.copy` and `table.init`
+	/// can be charged this way. Defaults to no additional charge.
+	#[cfg(feature = "bulk")]
+	fn bulk_cost(&self, _instruction: &BulkInstruction) -> Option<NonZeroU32> {
+		None
+	}
 }
 
 /// Dynamic costs for memory growth.
-#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum MemoryGrowCost {
 	/// Charge the specified amount for each page that the memory is grown by.
 	Linear(NonZeroU32),
+	/// Charge for each page that the memory is grown by the cost of the first tier which
+	/// `(max_pages, cost)` admits the requested amount of pages. The tiers must be ordered by
+	/// `max_pages`. Requests exceeding every tier are charged the cost of the last one.
+	Tiered(Vec<(u32, NonZeroU32)>),
+	/// Charge the specified amount for each page of the memory size after growing, i.e. the
+	/// already allocated pages are paid for again with every `memory.grow`.
+	TotalSize(NonZeroU32),
+	/// Trap if more than `max_pages` are requested at once and charge `cost` otherwise.
+	Capped {
+		max_pages: u32,
+		cost: Box<MemoryGrowCost>,
+	},
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
@@ -66,6 +151,14 @@ pub enum InstructionType {
 	Nop,
 	CurrentMemory,
 	GrowMemory,
+	#[cfg(feature = "bulk")]
+	Bulk,
+	#[cfg(feature = "sign_ext")]
+	SignExtension,
+	#[cfg(feature = "atomics")]
+	Atomic,
+	#[cfg(feature = "simd")]
+	Simd,
 }
 
 impl FromStr for InstructionType {
@@ -85,6 +178,7 @@ impl FromStr for InstructionType {
 			"flow" => Ok(InstructionType::ControlFlow),
 			"integer_comp" => Ok(InstructionType::IntegerComparison),
 			"float_comp" => Ok(InstructionType::FloatComparison),
+			"float_const" => Ok(InstructionType::FloatConst),
 			"float" => Ok(InstructionType::Float),
 			"conversion" => Ok(InstructionType::Conversion),
 			"float_conversion" => Ok(InstructionType::FloatConversion),
@@ -93,11 +187,55 @@ impl FromStr for InstructionType {
 			"nop" => Ok(InstructionType::Nop),
 			"current_mem" => Ok(InstructionType::CurrentMemory),
 			"grow_mem" => Ok(InstructionType::GrowMemory),
+			#[cfg(feature = "bulk")]
+			"bulk" => Ok(InstructionType::Bulk),
+			#[cfg(feature = "sign_ext")]
+			"sign_ext" => Ok(InstructionType::SignExtension),
+			#[cfg(feature = "atomics")]
+			"atomic" => Ok(InstructionType::Atomic),
+			#[cfg(feature = "simd")]
+			"simd" => Ok(InstructionType::Simd),
 			_ => Err(UnknownInstruction),
 		}
 	}
 }
 
+impl fmt::Display for InstructionType {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		f.write_str(match self {
+			InstructionType::Bit => "bit",
+			InstructionType::Add => "add",
+			InstructionType::Mul => "mul",
+			InstructionType::Div => "div",
+			InstructionType::Load => "load",
+			InstructionType::Store => "store",
+			InstructionType::Const => "const",
+			InstructionType::FloatConst => "float_const",
+			InstructionType::Local => "local",
+			InstructionType::Global => "global",
+			InstructionType::ControlFlow => "flow",
+			InstructionType::IntegerComparison => "integer_comp",
+			InstructionType::FloatComparison => "float_comp",
+			InstructionType::Float => "float",
+			InstructionType::Conversion => "conversion",
+			InstructionType::FloatConversion => "float_conversion",
+			InstructionType::Reinterpretation => "reinterpret",
+			InstructionType::Unreachable => "unreachable",
+			InstructionType::Nop => "nop",
+			InstructionType::CurrentMemory => "current_mem",
+			InstructionType::GrowMemory => "grow_mem",
+			#[cfg(feature = "bulk")]
+			InstructionType::Bulk => "bulk",
+			#[cfg(feature = "sign_ext")]
+			InstructionType::SignExtension => "sign_ext",
+			#[cfg(feature = "atomics")]
+			InstructionType::Atomic => "atomic",
+			#[cfg(feature = "simd")]
+			InstructionType::Simd => "simd",
+		})
+	}
+}
+
 impl InstructionType {
 	pub fn op(instruction: &Instruction) -> Self {
 		use Instruction::*;
@@ -291,14 +429,40 @@ impl InstructionType {
 			I64ReinterpretF64 => InstructionType::Reinterpretation,
 			F32ReinterpretI32 => InstructionType::Reinterpretation,
 			F64ReinterpretI64 => InstructionType::Reinterpretation,
+
+			#[cfg(feature = "bulk")]
+			Bulk(_) => InstructionType::Bulk,
+			#[cfg(feature = "sign_ext")]
+			SignExt(_) => InstructionType::SignExtension,
+			#[cfg(feature = "atomics")]
+			Atomics(_) => InstructionType::Atomic,
+			#[cfg(feature = "simd")]
+			Simd(_) => InstructionType::Simd,
+		}
+	}
+}
+
+impl fmt::Display for Metering {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			Metering::Regular => write!(f, "regular"),
+			Metering::Forbidden => write!(f, "forbidden"),
+			Metering::Fixed(val) => write!(f, "{}", val),
 		}
 	}
 }
 
-#[derive(Debug)]
+/// A rule set which assigns costs to classes of instructions.
+///
+/// Costs of single opcodes can be overridden, which takes precedence over the cost of
+/// their class. Calls to imported functions can be charged an additional cost per import.
+#[derive(Debug, PartialEq, Eq)]
 pub struct Set {
 	regular: u32,
 	entries: Map<InstructionType, Metering>,
+	opcodes: Map<Opcode, Metering>,
+	/// Costs of calling imported functions by module and field name.
+	imports: Map<String, Map<String, u32>>,
 	grow: u32,
 }
 
@@ -307,6 +471,8 @@ impl Default for Set {
 		Set {
 			regular: 1,
 			entries: Map::new(),
+			opcodes: Map::new(),
+			imports: Map::new(),
 			grow: 0,
 		}
 	}
@@ -314,7 +480,19 @@ impl Default for Set {
 
 impl Set {
 	pub fn new(regular: u32, entries: Map<InstructionType, Metering>) -> Self {
-		Set { regular, entries, grow: 0 }
+		Set { regular, entries, opcodes: Map::new(), imports: Map::new(), grow: 0 }
+	}
+
+	/// Overrides the metering of the `opcode` regardless of its class.
+	pub fn with_opcode_metering(mut self, opcode: Opcode, metering: Metering) -> Self {
+		self.opcodes.insert(opcode, metering);
+		self
+	}
+
+	/// Charges `cost` for every call to the imported function `field` of the module `module`.
+	pub fn with_import_call_cost(mut self, module: &str, field: &str, cost: u32) -> Self {
+		self.imports.entry(module.to_string()).or_default().insert(field.to_string(), cost);
+		self
 	}
 
 	pub fn grow_cost(&self) -> u32 {
@@ -336,10 +514,12 @@ impl Set {
 }
 
 impl Rules for Set {
-	fn instruction_cost(&self, instruction: &Instruction) -> Option<u32> {
-		match self.entries.get(&InstructionType::op(instruction)) {
-			None | Some(Metering::Regular) => Some(self.regular),
-			Some(Metering::Fixed(val)) => Some(*val),
+	fn instruction_cost(&self, instruction: &Instruction) -> Option<u64> {
+		let metering = self.opcodes.get(&Opcode::of(instruction))
+			.or_else(|| self.entries.get(&InstructionType::op(instruction)));
+		match metering {
+			None | Some(Metering::Regular) => Some(self.regular.into()),
+			Some(Metering::Fixed(val)) => Some((*val).into()),
 			Some(Metering::Forbidden) => None,
 		}
 	}
@@ -351,4 +531,328 @@ impl Rules for Set {
 			None
 		}
 	}
+
+	fn import_call_cost(&self, module: &str, field: &str) -> u64 {
+		self.imports
+			.get(module)
+			.and_then(|fields| fields.get(field))
+			.map_or(0, |cost| (*cost).into())
+	}
+}
+
+/// Sections of the textual rule set format.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Section {
+	Classes,
+	Opcodes,
+	Imports,
+}
+
+/// Parses a rule set from a simple textual cost table.
+///
+/// Every non-empty line is a `key = value` pair. Everything after a `#` is a comment.
+///
+/// - `regular` sets the cost of all instructions which class is not listed. Defaults to `1`.
+/// - `grow` sets the cost of every page `memory.grow` requests. Defaults to `0`.
+/// - Any other key is an instruction class as accepted by `InstructionType::from_str`, e.g.
+///   `load` or `grow_mem`. The value is either a cost, `regular` or `forbidden`.
+///
+/// All pairs following an `[opcodes]` line override the metering of single opcodes named as in
+/// the WebAssembly text format, e.g. `i32.div_s`. Values are the same as for classes.
+///
+/// All pairs following an `[imports]` line set the cost of calling the imported function named
+/// by the key in the form `module.field`. The module name must not contain a `.`.
+///
+/// Values can be quoted which makes TOML files of this shape acceptable as well. The `Display`
+/// implementation of `Set` produces this format.
+///
+/// ```text
+/// regular = 1
+/// grow = 8192
+///
+/// div = 16
+/// float = forbidden
+///
+/// [opcodes]
+/// i64.rem_u = 24
+///
+/// [imports]
+/// env.ext_hash = 1000
+/// ```
+impl FromStr for Set {
+	type Err = ParseError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut set = Set::default();
+		let mut seen_regular = false;
+		let mut seen_grow = false;
+		let mut section = Section::Classes;
+
+		for (index, line) in s.lines().enumerate() {
+			let error = |kind| ParseError { line: index + 1, kind };
+
+			let line = line.split('#').next().unwrap_or("").trim();
+			if line.is_empty() {
+				continue;
+			}
+
+			if line.starts_with('[') && line.ends_with(']') {
+				let name = line[1..line.len() - 1].trim();
+				let next = match name {
+					"opcodes" => Section::Opcodes,
+					"imports" => Section::Imports,
+					_ => return Err(error(ParseErrorKind::UnknownSection(name.to_string()))),
+				};
+				// Sections are in a fixed order, which makes them appear at most once.
+				if next <= section {
+					return Err(error(ParseErrorKind::UnknownSection(name.to_string())));
+				}
+				section = next;
+				continue;
+			}
+
+			let mut pair = line.splitn(2, '=');
+			let key = pair.next().unwrap_or("").trim();
+			let value = pair.next()
+				.ok_or_else(|| error(ParseErrorKind::MissingSeparator))?
+				.trim()
+				.trim_matches('"');
+			let invalid_value = || error(ParseErrorKind::InvalidValue(value.to_string()));
+			let duplicate_key = || error(ParseErrorKind::DuplicateKey(key.to_string()));
+
+			let parse_metering = || match value {
+				"regular" => Ok(Metering::Regular),
+				"forbidden" => Ok(Metering::Forbidden),
+				_ => value.parse().map(Metering::Fixed).map_err(|_| invalid_value()),
+			};
+
+			if section == Section::Opcodes {
+				let opcode = Opcode::from_str(key)
+					.map_err(|_| error(ParseErrorKind::UnknownOpcode(key.to_string())))?;
+				if set.opcodes.insert(opcode, parse_metering()?).is_some() {
+					return Err(duplicate_key());
+				}
+				continue;
+			}
+
+			if section == Section::Imports {
+				let key = key.trim_matches('"');
+				let mut name = key.splitn(2, '.');
+				let (module, field) = match (name.next(), name.next()) {
+					(Some(module), Some(field)) if !module.is_empty() && !field.is_empty() =>
+						(module.to_string(), field.to_string()),
+					_ => return Err(error(ParseErrorKind::InvalidImport(key.to_string()))),
+				};
+				let cost = value.parse().map_err(|_| invalid_value())?;
+				if set.imports.entry(module).or_default().insert(field, cost).is_some() {
+					return Err(duplicate_key());
+				}
+				continue;
+			}
+
+			match key {
+				"regular" => {
+					if seen_regular {
+						return Err(duplicate_key());
+					}
+					seen_regular = true;
+					set.regular = value.parse().map_err(|_| invalid_value())?;
+				}
+				"grow" => {
+					if seen_grow {
+						return Err(duplicate_key());
+					}
+					seen_grow = true;
+					set.grow = value.parse().map_err(|_| invalid_value())?;
+				}
+				_ => {
+					let class = InstructionType::from_str(key)
+						.map_err(|_| error(ParseErrorKind::UnknownClass(key.to_string())))?;
+					if set.entries.insert(class, parse_metering()?).is_some() {
+						return Err(duplicate_key());
+					}
+				}
+			}
+		}
+
+		Ok(set)
+	}
+}
+
+impl fmt::Display for Set {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		writeln!(f, "regular = {}", self.regular)?;
+		writeln!(f, "grow = {}", self.grow)?;
+		for (class, metering) in self.entries.iter() {
+			writeln!(f, "{} = {}", class, metering)?;
+		}
+		if !self.opcodes.is_empty() {
+			writeln!(f, "\n[opcodes]")?;
+			for (opcode, metering) in self.opcodes.iter() {
+				writeln!(f, "{} = {}", opcode, metering)?;
+			}
+		}
+		if !self.imports.is_empty() {
+			writeln!(f, "\n[imports]")?;
+			for (module, fields) in self.imports.iter() {
+				for (field, cost) in fields.iter() {
+					writeln!(f, "{}.{} = {}", module, field, cost)?;
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_set() {
+		let set: Set = r#"
+# Costs of our chain.
+regular = 2
+grow = "8192"
+
+div = 16 # Division is slow.
+float = forbidden
+load = regular
+"#.parse().expect("Failed to parse the rule set");
+
+		assert_eq!(set.instruction_cost(&Instruction::I32Add), Some(2));
+		assert_eq!(set.instruction_cost(&Instruction::I64RemU), Some(16));
+		assert_eq!(set.instruction_cost(&Instruction::F32Add), None);
+		assert_eq!(set.instruction_cost(&Instruction::I32Load(2, 0)), Some(2));
+		assert_eq!(
+			set.memory_grow_cost(),
+			Some(MemoryGrowCost::Linear(NonZeroU32::new(8192).unwrap())),
+		);
+	}
+
+	#[test]
+	fn parse_empty_set_is_default() {
+		assert_eq!("".parse::<Set>(), Ok(Set::default()));
+	}
+
+	#[test]
+	fn parse_errors() {
+		assert_eq!(
+			"regular = 1\n\nfancy = 2".parse::<Set>(),
+			Err(ParseError { line: 3, kind: ParseErrorKind::UnknownClass("fancy".into()) }),
+		);
+		assert_eq!(
+			"div 2".parse::<Set>(),
+			Err(ParseError { line: 1, kind: ParseErrorKind::MissingSeparator }),
+		);
+		assert_eq!(
+			"div = -2".parse::<Set>(),
+			Err(ParseError { line: 1, kind: ParseErrorKind::InvalidValue("-2".into()) }),
+		);
+		assert_eq!(
+			"div = 2\ndiv = 3".parse::<Set>(),
+			Err(ParseError { line: 2, kind: ParseErrorKind::DuplicateKey("div".into()) }),
+		);
+	}
+
+	#[test]
+	fn opcode_overrides_class() {
+		let set = Set::default()
+			.with_forbidden_floats()
+			.with_opcode_metering(Opcode::I64RemU, Metering::Fixed(24))
+			.with_opcode_metering(Opcode::F32Abs, Metering::Regular);
+
+		assert_eq!(set.instruction_cost(&Instruction::I64RemU), Some(24));
+		assert_eq!(set.instruction_cost(&Instruction::I32DivS), Some(1));
+		assert_eq!(set.instruction_cost(&Instruction::F32Abs), Some(1));
+		assert_eq!(set.instruction_cost(&Instruction::F32Neg), None);
+	}
+
+	#[test]
+	fn parse_opcodes() {
+		let set: Set = r#"
+div = 16
+
+[opcodes]
+i64.rem_u = 24
+call = 3
+"#.parse().expect("Failed to parse the rule set");
+
+		assert_eq!(set.instruction_cost(&Instruction::I32DivS), Some(16));
+		assert_eq!(set.instruction_cost(&Instruction::I64RemU), Some(24));
+		assert_eq!(set.instruction_cost(&Instruction::Call(0)), Some(3));
+		assert_eq!(set.instruction_cost(&Instruction::Drop), Some(1));
+
+		assert_eq!(
+			"[opcodes]\ndiv = 16".parse::<Set>(),
+			Err(ParseError { line: 2, kind: ParseErrorKind::UnknownOpcode("div".into()) }),
+		);
+		assert_eq!(
+			"[classes]".parse::<Set>(),
+			Err(ParseError { line: 1, kind: ParseErrorKind::UnknownSection("classes".into()) }),
+		);
+	}
+
+	#[cfg(all(feature = "sign_ext", feature = "atomics"))]
+	#[test]
+	fn parse_proposal_classes() {
+		use parity_wasm::elements::{AtomicsInstruction, MemArg, SignExtInstruction};
+
+		let set: Set = r#"
+sign_ext = 2
+atomic = 10
+
+[opcodes]
+memory.atomic.wait32 = 1000
+"#.parse().expect("Failed to parse the rule set");
+		let mem_arg = || MemArg { align: 2, offset: 0 };
+
+		assert_eq!(set.instruction_cost(&Instruction::SignExt(SignExtInstruction::I32Extend8S)), Some(2));
+		assert_eq!(
+			set.instruction_cost(&Instruction::Atomics(AtomicsInstruction::I32AtomicRmwAdd(mem_arg()))),
+			Some(10),
+		);
+		assert_eq!(
+			set.instruction_cost(&Instruction::Atomics(AtomicsInstruction::I32AtomicWait(mem_arg()))),
+			Some(1000),
+		);
+		assert_eq!(set.to_string().parse::<Set>(), Ok(set));
+	}
+
+	#[test]
+	fn display_round_trip() {
+		let set = Set::default()
+			.with_grow_cost(8192)
+			.with_forbidden_floats()
+			.with_opcode_metering(Opcode::I64RemU, Metering::Fixed(24))
+			.with_opcode_metering(Opcode::Nop, Metering::Forbidden)
+			.with_import_call_cost("env", "ext_hash", 1000);
+
+		assert_eq!(set.to_string().parse::<Set>(), Ok(set));
+	}
+
+	#[test]
+	fn parse_imports() {
+		let set: Set = r#"
+[opcodes]
+call = 3
+
+[imports]
+env.ext_hash = 1000
+"seal0.seal_call" = 5000
+"#.parse().expect("Failed to parse the rule set");
+
+		assert_eq!(set.import_call_cost("env", "ext_hash"), 1000);
+		assert_eq!(set.import_call_cost("seal0", "seal_call"), 5000);
+		assert_eq!(set.import_call_cost("env", "ext_print"), 0);
+
+		assert_eq!(
+			"[imports]\next_hash = 1".parse::<Set>(),
+			Err(ParseError { line: 2, kind: ParseErrorKind::InvalidImport("ext_hash".into()) }),
+		);
+		assert_eq!(
+			"[imports]\n[opcodes]".parse::<Set>(),
+			Err(ParseError { line: 2, kind: ParseErrorKind::UnknownSection("opcodes".into()) }),
+		);
+	}
 }
@@ -0,0 +1,162 @@
+//! Costs that depend on the immediate arguments of instructions.
+
+#[cfg(feature = "bulk")]
+use crate::std::num::NonZeroU32;
+use crate::Instruction;
+#[cfg(feature = "bulk")]
+use parity_wasm::elements::BulkInstruction;
+
+use super::{MemoryGrowCost, Rules};
+
+/// A rule set which adds costs depending on the immediates of an instruction to the costs of
+/// an inner rule set.
+///
+/// All costs default to `0`, in which case the costs of the inner rule set are returned unchanged.
+/// Instructions forbidden by the inner rule set stay forbidden.
+///
+/// ```
+/// use pwasm_utils::rules::{Set, WithImmediateCosts};
+///
+/// let rules = WithImmediateCosts::new(Set::default())
+///     .with_br_table_target_cost(2)
+///     .with_call_indirect_cost(10);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WithImmediateCosts<R> {
+	inner: R,
+	br_table_target: u32,
+	call_indirect: u32,
+	unaligned_access: u32,
+}
+
+impl<R> WithImmediateCosts<R> {
+	pub fn new(inner: R) -> Self {
+		WithImmediateCosts {
+			inner,
+			br_table_target: 0,
+			call_indirect: 0,
+			unaligned_access: 0,
+		}
+	}
+
+	/// Returns the wrapped rule set.
+	pub fn into_inner(self) -> R {
+		self.inner
+	}
+
+	/// Charges `cost` for every target of a `br_table` in addition to its default target.
+	pub fn with_br_table_target_cost(mut self, cost: u32) -> Self {
+		self.br_table_target = cost;
+		self
+	}
+
+	/// Charges `cost` for every `call_indirect` on top of the cost of its class.
+	pub fn with_call_indirect_cost(mut self, cost: u32) -> Self {
+		self.call_indirect = cost;
+		self
+	}
+
+	/// Charges `cost` for every load and store which alignment hint is smaller than the natural
+	/// alignment of the accessed value.
+	pub fn with_unaligned_access_cost(mut self, cost: u32) -> Self {
+		self.unaligned_access = cost;
+		self
+	}
+
+	fn immediate_cost(&self, instruction: &Instruction) -> u64 {
+		match instruction {
+			Instruction::BrTable(br_table_data) => {
+				(br_table_data.table.len() as u64).saturating_mul(self.br_table_target.into())
+			}
+			Instruction::CallIndirect(_, _) => self.call_indirect.into(),
+			_ => match memory_access(instruction) {
+				Some((align, natural)) if align < natural => self.unaligned_access.into(),
+				_ => 0,
+			},
+		}
+	}
+}
+
+impl<R: Rules> Rules for WithImmediateCosts<R> {
+	fn instruction_cost(&self, instruction: &Instruction) -> Option<u64> {
+		// Saturating, so that overflows are reported by the gas injection instead of the
+		// instruction being treated as forbidden.
+		self.inner
+			.instruction_cost(instruction)
+			.map(|cost| cost.saturating_add(self.immediate_cost(instruction)))
+	}
+
+	fn memory_grow_cost(&self) -> Option<MemoryGrowCost> {
+		self.inner.memory_grow_cost()
+	}
+
+	fn import_call_cost(&self, module: &str, field: &str) -> u64 {
+		self.inner.import_call_cost(module, field)
+	}
+
+	#[cfg(feature = "bulk")]
+	fn bulk_cost(&self, instruction: &BulkInstruction) -> Option<NonZeroU32> {
+		self.inner.bulk_cost(instruction)
+	}
+}
+
+/// Returns the alignment hint and the natural alignment, both as exponent of 2, of the memory
+/// access done by `instruction`.
+fn memory_access(instruction: &Instruction) -> Option<(u32, u32)> {
+	use self::Instruction::*;
+
+	let (align, natural) = match *instruction {
+		I32Load8S(align, _) | I32Load8U(align, _) | I64Load8S(align, _) | I64Load8U(align, _) |
+		I32Store8(align, _) | I64Store8(align, _) => (align, 0),
+		I32Load16S(align, _) | I32Load16U(align, _) | I64Load16S(align, _) | I64Load16U(align, _) |
+		I32Store16(align, _) | I64Store16(align, _) => (align, 1),
+		I32Load(align, _) | F32Load(align, _) | I64Load32S(align, _) | I64Load32U(align, _) |
+		I32Store(align, _) | F32Store(align, _) | I64Store32(align, _) => (align, 2),
+		I64Load(align, _) | F64Load(align, _) | I64Store(align, _) | F64Store(align, _) => (align, 3),
+		_ => return None,
+	};
+	Some((align, natural))
+}
+
+#[cfg(test)]
+mod tests {
+	use parity_wasm::elements::BrTableData;
+	use super::*;
+	use crate::rules::{Metering, Opcode, Set};
+
+	#[test]
+	fn br_table_per_target() {
+		let rules = WithImmediateCosts::new(Set::default()).with_br_table_target_cost(3);
+		let br_table = |targets: usize| Instruction::BrTable(Box::new(BrTableData {
+			table: vec![0; targets].into_boxed_slice(),
+			default: 0,
+		}));
+
+		assert_eq!(rules.instruction_cost(&br_table(0)), Some(1));
+		assert_eq!(rules.instruction_cost(&br_table(4)), Some(13));
+		assert_eq!(rules.instruction_cost(&Instruction::Br(0)), Some(1));
+	}
+
+	#[test]
+	fn call_indirect_and_unaligned_access() {
+		let rules = WithImmediateCosts::new(Set::default())
+			.with_call_indirect_cost(10)
+			.with_unaligned_access_cost(5);
+
+		assert_eq!(rules.instruction_cost(&Instruction::Call(0)), Some(1));
+		assert_eq!(rules.instruction_cost(&Instruction::CallIndirect(0, 0)), Some(11));
+		assert_eq!(rules.instruction_cost(&Instruction::I64Load(3, 0)), Some(1));
+		assert_eq!(rules.instruction_cost(&Instruction::I64Load(2, 0)), Some(6));
+		assert_eq!(rules.instruction_cost(&Instruction::I32Store8(0, 16)), Some(1));
+		assert_eq!(rules.instruction_cost(&Instruction::I32Store16(0, 16)), Some(6));
+	}
+
+	#[test]
+	fn forbidden_stays_forbidden() {
+		let inner = Set::default().with_opcode_metering(Opcode::F32Load, Metering::Forbidden);
+		let rules = WithImmediateCosts::new(inner).with_unaligned_access_cost(5);
+
+		assert_eq!(rules.instruction_cost(&Instruction::F32Load(0, 0)), None);
+		assert_eq!(rules.instruction_cost(&Instruction::I32Load(0, 0)), Some(6));
+	}
+}
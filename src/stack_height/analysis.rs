@@ -0,0 +1,475 @@
+//! This module is used to report the stack costs of a module without instrumenting it.
+//!
+//! The stack costs are the ones `inject_limiter` charges for a call to the respective function,
+//! so the reported depths are the highest values the stack height global could reach.
+//!
+//! The call graph contains the direct calls as well as all functions an indirect call could
+//! possibly invoke. These are the functions of the element segments with the signature expected
+//! by the `call_indirect` instruction. If the table is imported or exported, the embedder may
+//! store any exported function in it, so these are included as well. Imported functions are
+//! assumed to not call back into the module.
+
+use crate::std::vec::Vec;
+
+use parity_wasm::elements;
+use super::{compute_stack_costs, resolve_func_type, with_extra_cost, Error, StackCostModel};
+
+/// Stack usage of a function in the function index space.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionStack {
+	func_idx: u32,
+	stack_cost: u32,
+	callees: Vec<u32>,
+	indirect_callees: Vec<u32>,
+	max_depth: Option<u64>,
+}
+
+impl FunctionStack {
+	/// Index of the function in the function index space.
+	pub fn func_idx(&self) -> u32 {
+		self.func_idx
+	}
+
+	/// Stack cost of the function, which is zero for imported functions.
+	pub fn stack_cost(&self) -> u32 {
+		self.stack_cost
+	}
+
+	/// Functions called directly by `call` instructions, ordered by their index and without
+	/// duplicates.
+	pub fn callees(&self) -> &[u32] {
+		&self.callees
+	}
+
+	/// Functions possibly called by `call_indirect` instructions, ordered by their index and
+	/// without duplicates.
+	pub fn indirect_callees(&self) -> &[u32] {
+		&self.indirect_callees
+	}
+
+	/// The highest sum of stack costs along any chain of calls starting with this function.
+	///
+	/// This is `None` if a recursive cycle is reachable from the function, in which case the
+	/// depth is unbounded.
+	pub fn max_depth(&self) -> Option<u64> {
+		self.max_depth
+	}
+}
+
+/// Stack usage of all functions of a module along with its recursive cycles.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackAnalysis {
+	functions: Vec<FunctionStack>,
+	cycles: Vec<Vec<u32>>,
+	max_depth: Option<u64>,
+}
+
+impl StackAnalysis {
+	/// Stack usage of every function, including imports, ordered by function index.
+	pub fn functions(&self) -> &[FunctionStack] {
+		&self.functions
+	}
+
+	/// Groups of functions which can call each other recursively, i.e. the strongly connected
+	/// components of the call graph which contain a cycle.
+	///
+	/// The functions of a group are ordered by their index and the groups by their first
+	/// function.
+	pub fn cycles(&self) -> &[Vec<u32>] {
+		&self.cycles
+	}
+
+	/// Upper bound of the stack height reachable by calling any function of the module from the
+	/// outside, i.e. the highest `FunctionStack::max_depth` of the exported functions, the start
+	/// function and the functions of the element segments.
+	///
+	/// This is `None` if a recursive cycle is reachable from one of them.
+	pub fn max_depth(&self) -> Option<u64> {
+		self.max_depth
+	}
+}
+
+/// Computes the stack costs of all functions, the call graph and the resulting stack depths.
+///
+/// The module is not modified. The function fails under the same conditions as `inject_limiter`
+/// does.
+pub fn analyze(module: &elements::Module, model: StackCostModel) -> Result<StackAnalysis, Error> {
+	analyze_with_extra_cost(module, model, 0)
+}
+
+/// Like `analyze`, but with `extra_cost` added to the stack cost of every defined function as
+/// described by `LimiterConfig::with_extra_stack_cost`.
+pub(crate) fn analyze_with_extra_cost(
+	module: &elements::Module,
+	model: StackCostModel,
+	extra_cost: u32,
+) -> Result<StackAnalysis, Error> {
+	let stack_costs = with_extra_cost(module, compute_stack_costs(module, model)?, extra_cost)?;
+	let (callees, indirect_callees) = callees(module)?;
+	let successors: Vec<Vec<u32>> = callees
+		.iter()
+		.zip(&indirect_callees)
+		.map(|(direct, indirect)| {
+			let mut successors: Vec<u32> = direct.iter().chain(indirect).cloned().collect();
+			successors.sort_unstable();
+			successors.dedup();
+			successors
+		})
+		.collect();
+	let components = strongly_connected_components(&successors);
+
+	let mut max_depths = vec![None; successors.len()];
+	let mut cycles = Vec::new();
+	// Components are emitted after all components reachable from them, so the depths of all
+	// callees outside of the component are known already.
+	for component in components {
+		let is_cycle = component.len() > 1 || successors[component[0] as usize].contains(&component[0]);
+		if is_cycle {
+			let mut cycle = component;
+			cycle.sort_unstable();
+			cycles.push(cycle);
+			continue;
+		}
+
+		let func_idx = component[0] as usize;
+		max_depths[func_idx] = successors[func_idx]
+			.iter()
+			.try_fold(0u64, |depth, callee| max_depths[*callee as usize].map(|d: u64| depth.max(d)))
+			.map(|depth| depth + stack_costs[func_idx] as u64);
+	}
+	cycles.sort_unstable();
+
+	let max_depth = entry_points(module)
+		.iter()
+		.try_fold(0u64, |depth, func_idx| {
+			max_depths.get(*func_idx as usize).cloned().flatten().map(|d| depth.max(d))
+		});
+
+	let functions = callees
+		.into_iter()
+		.zip(indirect_callees)
+		.zip(stack_costs)
+		.zip(max_depths)
+		.enumerate()
+		.map(|(func_idx, (((callees, indirect_callees), stack_cost), max_depth))| FunctionStack {
+			func_idx: func_idx as u32,
+			stack_cost,
+			callees,
+			indirect_callees,
+			max_depth,
+		})
+		.collect();
+
+	Ok(StackAnalysis { functions, cycles, max_depth })
+}
+
+/// Callees of each function in the function index space.
+type CallGraph = Vec<Vec<u32>>;
+
+/// Returns the sorted and deduplicated targets of the `call` and the possible targets of the
+/// `call_indirect` instructions of every function.
+fn callees(module: &elements::Module) -> Result<(CallGraph, CallGraph), Error> {
+	let func_imports = module.import_count(elements::ImportCountType::Function);
+	let bodies = module.code_section().map(|cs| cs.bodies()).unwrap_or(&[]);
+	let types = module.type_section().map(|ts| ts.types()).unwrap_or(&[]);
+	let functions = module.functions_space();
+
+	let mut table_functions = element_members(module);
+	if module.import_count(elements::ImportCountType::Table) > 0 || exports_table(module) {
+		table_functions.extend(exported_functions(module));
+	}
+	table_functions.sort_unstable();
+	table_functions.dedup();
+	table_functions.retain(|func_idx| (*func_idx as usize) < functions);
+	let table_functions = table_functions
+		.into_iter()
+		.map(|func_idx| Ok((func_idx, resolve_func_type(func_idx, module)?)))
+		.collect::<Result<Vec<_>, Error>>()?;
+
+	let mut callees = vec![Vec::new(); func_imports];
+	let mut indirect_callees = vec![Vec::new(); func_imports];
+	for body in bodies {
+		let mut direct = Vec::new();
+		let mut indirect = Vec::new();
+		for instruction in body.code().elements() {
+			match instruction {
+				elements::Instruction::Call(idx) if (*idx as usize) < functions => direct.push(*idx),
+				elements::Instruction::CallIndirect(type_idx, _) => {
+					if let Some(elements::Type::Function(signature)) = types.get(*type_idx as usize) {
+						indirect.extend(
+							table_functions
+								.iter()
+								.filter(|(_, func_type)| *func_type == signature)
+								.map(|(func_idx, _)| *func_idx),
+						);
+					}
+				},
+				_ => {},
+			}
+		}
+		direct.sort_unstable();
+		direct.dedup();
+		indirect.sort_unstable();
+		indirect.dedup();
+		callees.push(direct);
+		indirect_callees.push(indirect);
+	}
+	Ok((callees, indirect_callees))
+}
+
+/// Functions which can be called from outside of the module: exported functions, the start
+/// function and the functions of the element segments.
+fn entry_points(module: &elements::Module) -> Vec<u32> {
+	let mut entry_points = exported_functions(module);
+	entry_points.extend(module.start_section());
+	entry_points.extend(element_members(module));
+	entry_points
+}
+
+fn exported_functions(module: &elements::Module) -> Vec<u32> {
+	module
+		.export_section()
+		.map(|es| es.entries())
+		.unwrap_or(&[])
+		.iter()
+		.filter_map(|entry| match entry.internal() {
+			elements::Internal::Function(func_idx) => Some(*func_idx),
+			_ => None,
+		})
+		.collect()
+}
+
+fn exports_table(module: &elements::Module) -> bool {
+	module
+		.export_section()
+		.map(|es| es.entries())
+		.unwrap_or(&[])
+		.iter()
+		.any(|entry| matches!(entry.internal(), elements::Internal::Table(_)))
+}
+
+fn element_members(module: &elements::Module) -> Vec<u32> {
+	module
+		.elements_section()
+		.map(|es| es.entries())
+		.unwrap_or(&[])
+		.iter()
+		.flat_map(|segment| segment.members())
+		.cloned()
+		.collect()
+}
+
+/// Finds the strongly connected components of the graph with the edges `successors` using
+/// Tarjan's algorithm.
+///
+/// Each component is emitted after all components reachable from it.
+fn strongly_connected_components(successors: &[Vec<u32>]) -> Vec<Vec<u32>> {
+	const UNVISITED: u32 = u32::MAX;
+
+	let mut index = vec![UNVISITED; successors.len()];
+	let mut low_link = vec![0; successors.len()];
+	let mut on_stack = vec![false; successors.len()];
+	let mut stack = Vec::new();
+	let mut components = Vec::new();
+	let mut next_index = 0;
+
+	for root in 0..successors.len() {
+		if index[root] != UNVISITED {
+			continue;
+		}
+
+		// Nodes currently being visited along with the position of the next successor to visit.
+		let mut visiting = vec![(root, 0)];
+		index[root] = next_index;
+		low_link[root] = next_index;
+		next_index += 1;
+		stack.push(root);
+		on_stack[root] = true;
+
+		while let Some(&mut (node, ref mut next)) = visiting.last_mut() {
+			if let Some(&successor) = successors[node].get(*next) {
+				*next += 1;
+				let successor = successor as usize;
+				if index[successor] == UNVISITED {
+					index[successor] = next_index;
+					low_link[successor] = next_index;
+					next_index += 1;
+					stack.push(successor);
+					on_stack[successor] = true;
+					visiting.push((successor, 0));
+				} else if on_stack[successor] {
+					low_link[node] = low_link[node].min(index[successor]);
+				}
+				continue;
+			}
+
+			visiting.pop();
+			if let Some(&(parent, _)) = visiting.last() {
+				low_link[parent] = low_link[parent].min(low_link[node]);
+			}
+			if low_link[node] == index[node] {
+				let mut component = Vec::new();
+				loop {
+					let member = stack.pop().expect("the node itself is on the stack; qed");
+					on_stack[member] = false;
+					component.push(member as u32);
+					if member == node {
+						break;
+					}
+				}
+				components.push(component);
+			}
+		}
+	}
+
+	components
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate wabt;
+
+	use parity_wasm::elements;
+	use super::*;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	fn analyze_wat(source: &str) -> StackAnalysis {
+		analyze(&parse_wat(source), StackCostModel::LocalDeclarations)
+			.expect("Failed to analyze the module")
+	}
+
+	#[test]
+	fn call_chain() {
+		let analysis = analyze_wat(r#"
+(module
+	(import "env" "ext" (func $ext))
+	(func $leaf (result i32)
+		i32.const 1
+	)
+	(func $middle (result i32)
+		call $ext
+		call $leaf
+		call $leaf
+		i32.add
+	)
+	(func $top
+		call $middle
+		drop
+		call $leaf
+		drop
+	)
+)
+"#);
+
+		let functions = analysis.functions();
+		assert_eq!(functions.len(), 4);
+		assert_eq!(functions[0].stack_cost(), 0);
+		assert_eq!(functions[0].max_depth(), Some(0));
+		assert_eq!(functions[1].stack_cost(), 1);
+		assert_eq!(functions[1].max_depth(), Some(1));
+		assert_eq!(functions[2].callees(), &[0, 1]);
+		assert_eq!(functions[2].stack_cost(), 2);
+		assert_eq!(functions[2].max_depth(), Some(3));
+		assert_eq!(functions[3].callees(), &[1, 2]);
+		assert_eq!(functions[3].max_depth(), Some(4));
+		assert!(analysis.cycles().is_empty());
+	}
+
+	#[test]
+	fn recursion_is_unbounded() {
+		let analysis = analyze_wat(r#"
+(module
+	(func $self
+		call $self
+	)
+	(func $ping
+		call $pong
+	)
+	(func $pong
+		call $ping
+	)
+	(func $caller
+		call $pong
+	)
+	(func $leaf)
+)
+"#);
+
+		assert_eq!(analysis.cycles(), &[vec![0], vec![1, 2]]);
+		let depths: Vec<_> = analysis.functions().iter().map(|f| f.max_depth()).collect();
+		assert_eq!(depths, vec![None, None, None, None, Some(0)]);
+	}
+
+	#[test]
+	fn indirect_calls() {
+		let analysis = analyze_wat(r#"
+(module
+	(type $unary (func (param i32) (result i32)))
+	(table 3 anyfunc)
+	(elem (i32.const 0) $id $nullary $deep)
+	(func $id (param i32) (result i32)
+		get_local 0
+	)
+	(func $nullary (result i32)
+		i32.const 1
+	)
+	(func $deep (param i32) (result i32)
+		get_local 0
+		get_local 0
+		get_local 0
+		i32.add
+		i32.add
+	)
+	(func $dispatch (export "dispatch") (param i32) (result i32)
+		get_local 0
+		get_local 0
+		call_indirect (type $unary)
+	)
+)
+"#);
+
+		let dispatch = &analysis.functions()[3];
+		assert!(dispatch.callees().is_empty());
+		assert_eq!(dispatch.indirect_callees(), &[0, 2]);
+		assert_eq!(dispatch.max_depth(), Some(2 + 3));
+		assert_eq!(analysis.max_depth(), Some(5));
+	}
+
+	#[test]
+	fn exported_table_can_hold_exported_functions() {
+		let analysis = analyze_wat(r#"
+(module
+	(table (export "table") 1 anyfunc)
+	(func $dispatch (export "dispatch")
+		i32.const 0
+		call_indirect
+	)
+)
+"#);
+
+		assert_eq!(analysis.functions()[0].indirect_callees(), &[0]);
+		assert_eq!(analysis.cycles(), &[vec![0]]);
+		assert_eq!(analysis.max_depth(), None);
+	}
+
+	#[test]
+	fn unreachable_recursion_is_bounded() {
+		let analysis = analyze_wat(r#"
+(module
+	(func $recursive
+		call $recursive
+	)
+	(func (export "main") (result i32)
+		i32.const 1
+	)
+)
+"#);
+
+		assert_eq!(analysis.cycles(), &[vec![0]]);
+		assert_eq!(analysis.max_depth(), Some(1));
+	}
+}
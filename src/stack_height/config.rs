@@ -0,0 +1,138 @@
+//! Configuration of the stack height limiter.
+
+use super::{StackCostModel, StackHeightGlobal};
+
+/// How the stack cost of functions called indirectly is charged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndirectCalls {
+	/// Replace every function of the table by a thunk charging its stack cost.
+	Thunks,
+	/// Charge the stack cost at every `call_indirect`, looking it up by the table index in a
+	/// generated function.
+	///
+	/// This adds a single function instead of one per table entry. It requires the table
+	/// contents to be known statically, otherwise `Thunks` are used: the table must be neither
+	/// imported nor exported, modified by table instructions of the bulk memory proposal nor
+	/// initialized at non constant offsets.
+	CostLookup,
+}
+
+/// Describes how `inject_limiter_with_config` instruments a module.
+///
+/// By default the stack costs are computed by `StackCostModel::LocalDeclarations`, the stack
+/// height is tracked in an internal global, every module is instrumented and indirect calls are
+/// charged by thunks.
+///
+/// ```
+/// use pwasm_utils::stack_height::{LimiterConfig, StackCostModel, StackHeightGlobal, ValueSizes};
+///
+/// let config = LimiterConfig::new(64 * 1024)
+///     .with_cost_model(StackCostModel::Sized(ValueSizes::bytes()))
+///     .with_global(StackHeightGlobal::Exported("stack_height"))
+///     .with_skip_bounded(true);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LimiterConfig<'a> {
+	stack_limit: u32,
+	cost_model: StackCostModel,
+	global: StackHeightGlobal<'a>,
+	skip_bounded: bool,
+	elide_bounded_calls: bool,
+	indirect_calls: IndirectCalls,
+	extra_stack_cost: u32,
+}
+
+impl<'a> LimiterConfig<'a> {
+	/// Traps once the stack height exceeds `stack_limit`.
+	pub fn new(stack_limit: u32) -> Self {
+		LimiterConfig {
+			stack_limit,
+			cost_model: StackCostModel::LocalDeclarations,
+			global: StackHeightGlobal::Internal,
+			skip_bounded: false,
+			elide_bounded_calls: false,
+			indirect_calls: IndirectCalls::Thunks,
+			extra_stack_cost: 0,
+		}
+	}
+
+	/// Sets how the stack costs of the functions are computed.
+	pub fn with_cost_model(mut self, cost_model: StackCostModel) -> Self {
+		self.cost_model = cost_model;
+		self
+	}
+
+	/// Sets where the global tracking the stack height comes from.
+	pub fn with_global(mut self, global: StackHeightGlobal<'a>) -> Self {
+		self.global = global;
+		self
+	}
+
+	/// Sets whether to leave the code untouched if the stack height provably never exceeds the
+	/// limit, i.e. if `StackAnalysis::max_depth` is at most the limit.
+	///
+	/// Modules importing functions are always instrumented, as the imports may call back into the
+	/// module. An exported or imported global is still added, so the interface of the module
+	/// doesn't depend on the outcome.
+	pub fn with_skip_bounded(mut self, skip_bounded: bool) -> Self {
+		self.skip_bounded = skip_bounded;
+		self
+	}
+
+	/// Sets whether to leave calls to functions with a bounded call tree uninstrumented and to
+	/// add their worst-case stack depth to the stack cost of the caller instead.
+	///
+	/// Indirect calls are still charged at the call site, so the depth only covers the direct
+	/// calls of the bounded call tree.
+	///
+	/// This greatly reduces the code size, as only calls to functions which may recurse are
+	/// instrumented, at the price of charging the bounded call trees even when they aren't called.
+	pub fn with_elide_bounded_calls(mut self, elide_bounded_calls: bool) -> Self {
+		self.elide_bounded_calls = elide_bounded_calls;
+		self
+	}
+
+	/// Sets how the stack cost of functions called indirectly is charged.
+	pub fn with_indirect_calls(mut self, indirect_calls: IndirectCalls) -> Self {
+		self.indirect_calls = indirect_calls;
+		self
+	}
+
+	/// Sets a cost added to the stack cost of every function defined in the module.
+	///
+	/// This accounts for code added to the functions after injecting the limiter, e.g. the values
+	/// gas metering pushes onto the value stack. `instrument_with_config` adds the cost of its gas
+	/// metering to the one set here.
+	pub fn with_extra_stack_cost(mut self, extra_stack_cost: u32) -> Self {
+		self.extra_stack_cost = extra_stack_cost;
+		self
+	}
+
+	pub fn stack_limit(&self) -> u32 {
+		self.stack_limit
+	}
+
+	pub fn cost_model(&self) -> StackCostModel {
+		self.cost_model
+	}
+
+	pub fn global(&self) -> StackHeightGlobal<'a> {
+		self.global
+	}
+
+	pub fn skip_bounded(&self) -> bool {
+		self.skip_bounded
+	}
+
+	pub fn elide_bounded_calls(&self) -> bool {
+		self.elide_bounded_calls
+	}
+
+	pub fn indirect_calls(&self) -> IndirectCalls {
+		self.indirect_calls
+	}
+
+	pub fn extra_stack_cost(&self) -> u32 {
+		self.extra_stack_cost
+	}
+}
@@ -0,0 +1,95 @@
+//! Stack cost models: `ValueSizes` and `StackCostModel`.
+
+use parity_wasm::elements::ValueType;
+
+/// Sizes of values by their type, in an arbitrary unit chosen by the embedder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueSizes {
+	i32: u32,
+	i64: u32,
+	f32: u32,
+	f64: u32,
+	#[cfg(feature = "simd")]
+	v128: u32,
+}
+
+impl ValueSizes {
+	/// Every value has the size `1`, except for `v128` values which have the size `2`.
+	///
+	/// This corresponds to an executor representing values by a union of 8 bytes.
+	pub fn uniform() -> Self {
+		ValueSizes {
+			i32: 1,
+			i64: 1,
+			f32: 1,
+			f64: 1,
+			#[cfg(feature = "simd")]
+			v128: 2,
+		}
+	}
+
+	/// Every value has its size in bytes, e.g. `4` for an `i32`.
+	pub fn bytes() -> Self {
+		ValueSizes {
+			i32: 4,
+			i64: 8,
+			f32: 4,
+			f64: 8,
+			#[cfg(feature = "simd")]
+			v128: 16,
+		}
+	}
+
+	/// Sets the size of values of type `value_type`.
+	pub fn with_size(mut self, value_type: ValueType, size: u32) -> Self {
+		*self.size_mut(value_type) = size;
+		self
+	}
+
+	/// Returns the size of a value of type `value_type`.
+	pub fn size_of(&self, value_type: ValueType) -> u32 {
+		match value_type {
+			ValueType::I32 => self.i32,
+			ValueType::I64 => self.i64,
+			ValueType::F32 => self.f32,
+			ValueType::F64 => self.f64,
+			#[cfg(feature = "simd")]
+			ValueType::V128 => self.v128,
+		}
+	}
+
+	fn size_mut(&mut self, value_type: ValueType) -> &mut u32 {
+		match value_type {
+			ValueType::I32 => &mut self.i32,
+			ValueType::I64 => &mut self.i64,
+			ValueType::F32 => &mut self.f32,
+			ValueType::F64 => &mut self.f64,
+			#[cfg(feature = "simd")]
+			ValueType::V128 => &mut self.v128,
+		}
+	}
+}
+
+/// Determines how the stack cost of a function is computed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackCostModel {
+	/// The number of local declarations plus the maximal height of the value stack, measured with
+	/// `ValueSizes::uniform`.
+	///
+	/// A single declaration can declare many locals, which are counted only once, and parameters
+	/// are not counted at all. This model only exists to reproduce the costs of earlier versions.
+	LocalDeclarations,
+	/// The sum of the sizes of all parameters and locals plus the maximal sum of the sizes of the
+	/// values on the value stack.
+	Sized(ValueSizes),
+}
+
+impl StackCostModel {
+	/// The sizes of the values on the value stack.
+	pub fn value_sizes(&self) -> ValueSizes {
+		match self {
+			StackCostModel::LocalDeclarations => ValueSizes::uniform(),
+			StackCostModel::Sized(sizes) => *sizes,
+		}
+	}
+}
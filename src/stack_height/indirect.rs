@@ -0,0 +1,289 @@
+//! This module is used to charge the stack cost of indirect calls at the call site.
+//!
+//! Instead of generating a thunk for every function of the table, each `call_indirect` looks
+//! up the stack cost of the called table entry in a function generated for this purpose. The
+//! lookup function maps the table index by a single `br_table` to one block per distinct stack
+//! cost, so its size grows with the number of table entries by one label each.
+
+use crate::std::boxed::Box;
+use crate::std::vec::Vec;
+
+use parity_wasm::builder;
+use parity_wasm::elements::{self, BlockType, BrTableData, Instruction, ValueType};
+use super::{resolve_func_type, Context, Error};
+
+/// Returns the function stored at each index of the table, if the table contents can't change
+/// at runtime.
+///
+/// That is the case unless the table is imported or exported, the module uses table
+/// instructions of the bulk memory proposal or an element segment has a non constant offset.
+pub(crate) fn static_table(module: &elements::Module) -> Option<Vec<Option<u32>>> {
+	if module.import_count(elements::ImportCountType::Table) > 0 {
+		return None;
+	}
+	let exports = module.export_section().map(|es| es.entries()).unwrap_or(&[]);
+	if exports.iter().any(|entry| matches!(entry.internal(), elements::Internal::Table(_))) {
+		return None;
+	}
+	#[cfg(feature = "bulk")]
+	{
+		use parity_wasm::elements::BulkInstruction::{TableCopy, TableInit};
+
+		let bodies = module.code_section().map(|cs| cs.bodies()).unwrap_or(&[]);
+		let mutates_table = bodies
+			.iter()
+			.flat_map(|body| body.code().elements())
+			.any(|instruction| matches!(instruction, Instruction::Bulk(TableInit(_)) | Instruction::Bulk(TableCopy)));
+		if mutates_table {
+			return None;
+		}
+	}
+
+	// Segments not fitting into the initial table fail the instantiation.
+	let table_size = match module.table_section().and_then(|ts| ts.entries().first()) {
+		Some(table_type) => table_type.limits().initial() as usize,
+		None => return Some(Vec::new()),
+	};
+
+	let mut table = Vec::new();
+	let segments = module.elements_section().map(|es| es.entries()).unwrap_or(&[]);
+	for segment in segments {
+		// Passive segments only end up in the table by `table.init`, which is rejected above.
+		let offset = match segment.offset().as_ref().map(|offset| offset.code()) {
+			Some([Instruction::I32Const(offset), Instruction::End]) => *offset as u32 as usize,
+			Some(_) => return None,
+			None => continue,
+		};
+		let end = offset + segment.members().len();
+		if end > table_size {
+			return None;
+		}
+		if table.len() < end {
+			table.resize(end, None);
+		}
+		for (entry, func_idx) in table[offset..end].iter_mut().zip(segment.members()) {
+			*entry = Some(*func_idx);
+		}
+	}
+	Some(table)
+}
+
+/// Instruments every `call_indirect` to charge the stack cost of the function stored at the
+/// called index of `table`.
+///
+/// The lookup function is added after all other functions.
+pub(crate) fn instrument_indirect_calls(
+	ctx: &Context,
+	mut module: elements::Module,
+	table: &[Option<u32>],
+) -> Result<elements::Module, Error> {
+	let costs = table
+		.iter()
+		.map(|entry| match entry {
+			Some(func_idx) => ctx.stack_cost(*func_idx).ok_or_else(|| {
+				Error(format!("function with idx {} isn't found", func_idx))
+			}),
+			None => Ok(0),
+		})
+		.collect::<Result<Vec<_>, Error>>()?;
+	if costs.iter().all(|cost| *cost == 0) {
+		return Ok(module);
+	}
+
+	let lookup_func = module.functions_space() as u32;
+	let func_imports = module.import_count(elements::ImportCountType::Function) as u32;
+	let defined_funcs = module.function_section().map(|fs| fs.entries().len()).unwrap_or(0) as u32;
+	let params = (func_imports..func_imports + defined_funcs)
+		.map(|func_idx| Ok(resolve_func_type(func_idx, &module)?.params().len() as u32))
+		.collect::<Result<Vec<_>, Error>>()?;
+
+	if let Some(code_section) = module.code_section_mut() {
+		for (func_body, params) in code_section.bodies_mut().iter_mut().zip(params) {
+			let has_indirect_calls = func_body
+				.code()
+				.elements()
+				.iter()
+				.any(|instruction| matches!(instruction, Instruction::CallIndirect(..)));
+			if !has_indirect_calls {
+				continue;
+			}
+
+			// Add a local to keep the table index for charging the stack cost back after the call.
+			let table_idx_local = params + func_body.locals().iter().map(|local| local.count()).sum::<u32>();
+			func_body.locals_mut().push(elements::Local::new(1, ValueType::I32));
+
+			let instructions = func_body.code_mut().elements_mut();
+			let mut cursor = 0;
+			while cursor < instructions.len() {
+				if let Instruction::CallIndirect(..) = instructions[cursor] {
+					let call_indirect = instructions[cursor].clone();
+					let new_seq = instrument_call_indirect(ctx, call_indirect, table_idx_local, lookup_func);
+					let _ = instructions.splice(cursor..(cursor + 1), new_seq.iter().cloned()).count();
+					cursor += new_seq.len();
+				} else {
+					cursor += 1;
+				}
+			}
+		}
+	}
+
+	let module = builder::from_module(module)
+		.function()
+			.signature()
+				.with_param(ValueType::I32)
+				.with_result(ValueType::I32)
+				.build()
+			.body()
+				.with_instructions(elements::Instructions::new(lookup_body(&costs)))
+				.build()
+			.build()
+		.build();
+
+	Ok(module)
+}
+
+/// Wraps `call_indirect` with a preamble and postamble charging the stack cost of the table entry
+/// whose index is on top of the value stack.
+fn instrument_call_indirect(
+	ctx: &Context,
+	call_indirect: Instruction,
+	table_idx_local: u32,
+	lookup_func: u32,
+) -> [Instruction; 18] {
+	use parity_wasm::elements::Instruction::*;
+
+	let stack_height_global_idx = ctx.stack_height_global_idx();
+	[
+		TeeLocal(table_idx_local),
+		// stack_height += lookup(table_idx)
+		GetGlobal(stack_height_global_idx),
+		GetLocal(table_idx_local),
+		Call(lookup_func),
+		I32Add,
+		SetGlobal(stack_height_global_idx),
+		// if stack_counter > LIMIT: unreachable
+		GetGlobal(stack_height_global_idx),
+		I32Const(ctx.stack_limit() as i32),
+		I32GtU,
+		If(BlockType::NoResult),
+		Unreachable,
+		End,
+		// Original call
+		call_indirect,
+		// stack_height -= lookup(table_idx)
+		GetGlobal(stack_height_global_idx),
+		GetLocal(table_idx_local),
+		Call(lookup_func),
+		I32Sub,
+		SetGlobal(stack_height_global_idx),
+	]
+}
+
+/// Body of the function returning the stack cost of the table entry at the index passed as its
+/// only argument.
+///
+/// For two distinct stack costs `a` and `b`:
+///
+/// ```text
+/// block           ;; returns b
+///   block         ;; returns a
+///     block       ;; returns 0
+///       get_local 0
+///       br_table ...
+///     end
+///     i32.const 0
+///     return
+///   end
+///   i32.const a
+///   return
+/// end
+/// i32.const b
+/// return
+/// ```
+fn lookup_body(costs: &[u32]) -> Vec<Instruction> {
+	use parity_wasm::elements::Instruction::*;
+
+	let mut distinct_costs: Vec<u32> = costs.iter().cloned().filter(|cost| *cost != 0).collect();
+	distinct_costs.sort_unstable();
+	distinct_costs.dedup();
+
+	let mut labels: Vec<u32> = costs
+		.iter()
+		.map(|cost| match distinct_costs.binary_search(cost) {
+			Ok(group) => group as u32 + 1,
+			Err(_) => 0,
+		})
+		.collect();
+	// Indices out of the table return 0 anyway.
+	while labels.last() == Some(&0) {
+		labels.pop();
+	}
+
+	let mut body = vec![Block(BlockType::NoResult); distinct_costs.len() + 1];
+	body.push(GetLocal(0));
+	body.push(BrTable(Box::new(BrTableData {
+		table: labels.into_boxed_slice(),
+		default: 0,
+	})));
+	body.extend_from_slice(&[End, I32Const(0), Return]);
+	for cost in distinct_costs {
+		body.extend_from_slice(&[End, I32Const(cost as i32), Return]);
+	}
+	body.push(End);
+	body
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate wabt;
+
+	use parity_wasm::elements;
+	use super::*;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	#[test]
+	fn static_table_entries() {
+		let module = parse_wat(r#"
+(module
+	(func $a)
+	(func $b)
+	(table 6 anyfunc)
+	(elem (i32.const 1) $a $b)
+	(elem (i32.const 2) $a)
+)
+"#);
+
+		assert_eq!(static_table(&module), Some(vec![None, Some(0), Some(0)]));
+	}
+
+	#[test]
+	fn exported_table_is_not_static() {
+		let module = parse_wat(r#"
+(module
+	(func $a)
+	(table (export "table") 1 anyfunc)
+	(elem (i32.const 0) $a)
+)
+"#);
+
+		assert_eq!(static_table(&module), None);
+	}
+
+	#[test]
+	fn global_offset_is_not_static() {
+		let module = parse_wat(r#"
+(module
+	(import "env" "offset" (global i32))
+	(func $a)
+	(table 1 anyfunc)
+	(elem (get_global 0) $a)
+)
+"#);
+
+		assert_eq!(static_table(&module), None);
+	}
+}
@@ -1,7 +1,7 @@
 use crate::std::vec::Vec;
 
-use parity_wasm::elements::{self, BlockType, Type};
-use super::{resolve_func_type, Error};
+use parity_wasm::elements::{self, BlockType, Type, ValueType};
+use super::{resolve_func_type, Error, ValueSizes};
 
 /// Control stack frame.
 #[derive(Debug)]
@@ -10,41 +10,53 @@ struct Frame {
 	/// never passes control further was executed.
 	is_polymorphic: bool,
 
-	/// Count of values which will be pushed after the exit
+	/// Types of the values which will be pushed after the exit
 	/// from the current block.
-	end_arity: u32,
+	end_types: Vec<ValueType>,
 
-	/// Count of values which should be poped upon a branch to
+	/// Types of the values which should be poped upon a branch to
 	/// this frame.
 	///
-	/// This might be diffirent from `end_arity` since branch
+	/// This might be diffirent from `end_types` since branch
 	/// to the loop header can't take any values.
-	branch_arity: u32,
+	branch_types: Vec<ValueType>,
 
-	/// Stack height before entering in the block.
-	start_height: u32,
+	/// Count of values on the value stack before entering in the block.
+	start_len: usize,
 }
 
 /// This is a compound stack that abstracts tracking height of the value stack
 /// and manipulation of the control stack.
 struct Stack {
+	/// Sizes of the values by their type.
+	sizes: ValueSizes,
+	/// Sizes of the values on the value stack.
+	values: Vec<u32>,
+	/// Sum of the sizes of all values on the value stack.
 	height: u32,
 	control_stack: Vec<Frame>,
 }
 
 impl Stack {
-	fn new() -> Stack {
+	fn new(sizes: ValueSizes) -> Stack {
 		Stack {
+			sizes,
+			values: Vec::new(),
 			height: 0,
 			control_stack: Vec::new(),
 		}
 	}
 
-	/// Returns current height of the value stack.
+	/// Returns current height of the value stack, i.e. the sum of the sizes of its values.
 	fn height(&self) -> u32 {
 		self.height
 	}
 
+	/// Returns current count of values on the value stack.
+	fn len(&self) -> usize {
+		self.values.len()
+	}
+
 	/// Returns a reference to a frame by specified depth relative to the top of
 	/// control stack.
 	fn frame(&self, rel_depth: u32) -> Result<&Frame, Error> {
@@ -86,56 +98,123 @@ impl Stack {
 			.ok_or_else(|| Error("stack must be non-empty".into()))?)
 	}
 
-	/// Truncate the height of value stack to the specified height.
-	fn trunc(&mut self, new_height: u32) {
-		trace!(target: "max_height", "trunc: {}", new_height);
-		self.height = new_height;
+	/// Truncate the value stack to the specified count of values.
+	fn trunc(&mut self, new_len: usize) {
+		trace!(target: "max_height", "trunc: {}", new_len);
+		for size in self.values.drain(new_len..) {
+			self.height -= size;
+		}
 	}
 
-	/// Push specified number of values into the value stack.
+	/// Push a value of the specified size into the value stack.
 	///
-	/// Returns `Err` if the height overflow usize value.
-	fn push_values(&mut self, value_count: u32) -> Result<(), Error> {
-		trace!(target: "max_height", "push: {}", value_count);
+	/// Returns `Err` if the height overflow u32 value.
+	fn push_value(&mut self, size: u32) -> Result<(), Error> {
 		self.height = self.height
-			.checked_add(value_count)
+			.checked_add(size)
 			.ok_or_else(|| Error("stack overflow".into()))?;
+		self.values.push(size);
+		Ok(())
+	}
+
+	/// Push a value of the specified type into the value stack.
+	fn push_type(&mut self, value_type: ValueType) -> Result<(), Error> {
+		trace!(target: "max_height", "push: {:?}", value_type);
+		self.push_value(self.sizes.size_of(value_type))
+	}
+
+	/// Push values of the specified types into the value stack.
+	fn push_types(&mut self, value_types: &[ValueType]) -> Result<(), Error> {
+		for value_type in value_types {
+			self.push_type(*value_type)?;
+		}
 		Ok(())
 	}
 
+	/// Pop a single value from the value stack and return its size.
+	///
+	/// Returns `None` if the value stems from the unreachable code of a polymorphic frame and
+	/// `Err` if the value was pushed by the parent frame.
+	fn pop_value(&mut self) -> Result<Option<u32>, Error> {
+		let top_frame = self.frame(0)?;
+		if self.values.len() == top_frame.start_len {
+			// It is an error to pop more values than was pushed in the current frame
+			// (ie pop values pushed in the parent frame), unless the frame became
+			// polymorphic.
+			return if top_frame.is_polymorphic {
+				Ok(None)
+			} else {
+				Err(Error("trying to pop more values than pushed".into()))
+			}
+		}
+
+		let size = self.values.pop().ok_or_else(|| Error("stack underflow".into()))?;
+		self.height -= size;
+		Ok(Some(size))
+	}
+
 	/// Pop specified number of values from the value stack.
 	///
 	/// Returns `Err` if the stack happen to be negative value after
 	/// values popped.
 	fn pop_values(&mut self, value_count: u32) -> Result<(), Error> {
 		trace!(target: "max_height", "pop: {}", value_count);
-		if value_count == 0 {
-			return Ok(());
-		}
-		{
-			let top_frame = self.frame(0)?;
-			if self.height == top_frame.start_height {
-				// It is an error to pop more values than was pushed in the current frame
-				// (ie pop values pushed in the parent frame), unless the frame became
-				// polymorphic.
-				return if top_frame.is_polymorphic {
-					Ok(())
-				} else {
-					Err(Error("trying to pop more values than pushed".into()))
-				}
+		for _ in 0..value_count {
+			if self.pop_value()?.is_none() {
+				break;
 			}
 		}
-
-		self.height = self.height
-			.checked_sub(value_count)
-			.ok_or_else(|| Error("stack underflow".into()))?;
-
 		Ok(())
 	}
 }
 
+/// Returns the type of the local variable `local_idx`, counting the parameters first.
+fn local_type(
+	local_idx: u32,
+	signature: &elements::FunctionType,
+	body: &elements::FuncBody,
+) -> Result<ValueType, Error> {
+	if let Some(param) = signature.params().get(local_idx as usize) {
+		return Ok(*param);
+	}
+	let mut idx = local_idx as u64 - signature.params().len() as u64;
+	for local in body.locals() {
+		if idx < local.count() as u64 {
+			return Ok(local.value_type());
+		}
+		idx -= local.count() as u64;
+	}
+	Err(Error(format!("Local {} is not defined", local_idx)))
+}
+
+/// Returns the type of the global `global_idx`, counting the imported globals first.
+fn global_type(global_idx: u32, module: &elements::Module) -> Result<ValueType, Error> {
+	let imported = module.import_section()
+		.map(|is| is.entries())
+		.unwrap_or(&[])
+		.iter()
+		.filter_map(|entry| match entry.external() {
+			elements::External::Global(global_type) => Some(global_type),
+			_ => None,
+		});
+	let defined = module.global_section()
+		.map(|gs| gs.entries())
+		.unwrap_or(&[])
+		.iter()
+		.map(|entry| entry.global_type());
+	imported
+		.chain(defined)
+		.nth(global_idx as usize)
+		.map(|global_type| global_type.content_type())
+		.ok_or_else(|| Error(format!("Global {} is not defined", global_idx)))
+}
+
 /// This function expects the function to be validated.
-pub(crate) fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, Error> {
+pub(crate) fn compute(
+	func_idx: u32,
+	module: &elements::Module,
+	sizes: ValueSizes,
+) -> Result<u32, Error> {
 	use parity_wasm::elements::Instruction::*;
 
 	let func_section = module
@@ -167,7 +246,7 @@ pub(crate) fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, E
 		.ok_or_else(|| Error("Function body for the index isn't found".into()))?;
 	let instructions = body.code();
 
-	let mut stack = Stack::new();
+	let mut stack = Stack::new(sizes);
 	let mut max_height: u32 = 0;
 	let mut pc = 0;
 
@@ -176,9 +255,9 @@ pub(crate) fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, E
 	let func_arity = func_signature.results().len() as u32;
 	stack.push_frame(Frame {
 		is_polymorphic: false,
-		end_arity: func_arity,
-		branch_arity: func_arity,
-		start_height: 0,
+		end_types: func_signature.results().to_vec(),
+		branch_types: func_signature.results().to_vec(),
+		start_len: 0,
 	});
 
 	loop {
@@ -199,17 +278,20 @@ pub(crate) fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, E
 		match opcode {
 			Nop => {}
 			Block(ty) | Loop(ty) | If(ty) => {
-				let end_arity = if *ty == BlockType::NoResult { 0 } else { 1 };
-				let branch_arity = if let Loop(_) = *opcode { 0 } else { end_arity };
+				let end_types = match *ty {
+					BlockType::NoResult => Vec::new(),
+					BlockType::Value(value_type) => vec![value_type],
+				};
+				let branch_types = if let Loop(_) = *opcode { Vec::new() } else { end_types.clone() };
 				if let If(_) = *opcode {
 					stack.pop_values(1)?;
 				}
-				let height = stack.height();
+				let start_len = stack.len();
 				stack.push_frame(Frame {
 					is_polymorphic: false,
-					end_arity,
-					branch_arity,
-					start_height: height,
+					end_types,
+					branch_types,
+					start_len,
 				});
 			}
 			Else => {
@@ -218,15 +300,15 @@ pub(crate) fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, E
 			}
 			End => {
 				let frame = stack.pop_frame()?;
-				stack.trunc(frame.start_height);
-				stack.push_values(frame.end_arity)?;
+				stack.trunc(frame.start_len);
+				stack.push_types(&frame.end_types)?;
 			}
 			Unreachable => {
 				stack.mark_unreachable()?;
 			}
 			Br(target) => {
 				// Pop values for the destination block result.
-				let target_arity = stack.frame(*target)?.branch_arity;
+				let target_arity = stack.frame(*target)?.branch_types.len() as u32;
 				stack.pop_values(target_arity)?;
 
 				// This instruction unconditionally transfers control to the specified block,
@@ -234,22 +316,22 @@ pub(crate) fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, E
 				stack.mark_unreachable()?;
 			}
 			BrIf(target) => {
-				// Pop values for the destination block result.
-				let target_arity = stack.frame(*target)?.branch_arity;
-				stack.pop_values(target_arity)?;
-
 				// Pop condition value.
 				stack.pop_values(1)?;
 
+				// Pop values for the destination block result.
+				let target_types = stack.frame(*target)?.branch_types.clone();
+				stack.pop_values(target_types.len() as u32)?;
+
 				// Push values back.
-				stack.push_values(target_arity)?;
+				stack.push_types(&target_types)?;
 			}
 			BrTable(br_table_data) => {
-				let arity_of_default = stack.frame(br_table_data.default)?.branch_arity;
+				let arity_of_default = stack.frame(br_table_data.default)?.branch_types.len();
 
 				// Check that all jump targets have an equal arities.
 				for target in &*br_table_data.table {
-					let arity = stack.frame(*target)?.branch_arity;
+					let arity = stack.frame(*target)?.branch_types.len();
 					if arity != arity_of_default {
 						return Err(Error(
 							"Arity of all jump-targets must be equal".into()
@@ -259,7 +341,7 @@ pub(crate) fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, E
 
 				// Because all jump targets have an equal arities, we can just take arity of
 				// the default branch.
-				stack.pop_values(arity_of_default)?;
+				stack.pop_values(arity_of_default as u32)?;
 
 				// This instruction doesn't let control flow to go further, since the control flow
 				// should take either one of branches depending on the value or the default branch.
@@ -278,8 +360,7 @@ pub(crate) fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, E
 				stack.pop_values(ty.params().len() as u32)?;
 
 				// Push result of the function execution to the stack.
-				let callee_arity = ty.results().len() as u32;
-				stack.push_values(callee_arity)?;
+				stack.push_types(ty.results())?;
 			}
 			CallIndirect(x, _) => {
 				let Type::Function(ty) = type_section
@@ -294,56 +375,64 @@ pub(crate) fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, E
 				stack.pop_values(ty.params().len() as u32)?;
 
 				// Push result of the function execution to the stack.
-				let callee_arity = ty.results().len() as u32;
-				stack.push_values(callee_arity)?;
+				stack.push_types(ty.results())?;
 			}
 			Drop => {
 				stack.pop_values(1)?;
 			}
 			Select => {
-				// Pop two values and one condition.
-				stack.pop_values(2)?;
+				// Pop one condition and two values of the same type.
+				stack.pop_values(1)?;
 				stack.pop_values(1)?;
+				let size = stack.pop_value()?;
 
 				// Push the selected value.
-				stack.push_values(1)?;
+				stack.push_value(size.unwrap_or(1))?;
 			}
-			GetLocal(_) => {
-				stack.push_values(1)?;
+			GetLocal(idx) => {
+				stack.push_type(local_type(*idx, func_signature, body)?)?;
 			}
 			SetLocal(_) => {
 				stack.pop_values(1)?;
 			}
-			TeeLocal(_) => {
+			TeeLocal(idx) => {
 				// This instruction pops and pushes the value, so
 				// effectively it doesn't modify the stack height.
 				stack.pop_values(1)?;
-				stack.push_values(1)?;
+				stack.push_type(local_type(*idx, func_signature, body)?)?;
 			}
-			GetGlobal(_) => {
-				stack.push_values(1)?;
+			GetGlobal(idx) => {
+				stack.push_type(global_type(*idx, module)?)?;
 			}
 			SetGlobal(_) => {
 				stack.pop_values(1)?;
 			}
 			I32Load(_, _)
-			| I64Load(_, _)
-			| F32Load(_, _)
-			| F64Load(_, _)
 			| I32Load8S(_, _)
 			| I32Load8U(_, _)
 			| I32Load16S(_, _)
-			| I32Load16U(_, _)
+			| I32Load16U(_, _) => {
+				// These instructions pop the address and pushes the result.
+				stack.pop_values(1)?;
+				stack.push_type(ValueType::I32)?;
+			}
+			I64Load(_, _)
 			| I64Load8S(_, _)
 			| I64Load8U(_, _)
 			| I64Load16S(_, _)
 			| I64Load16U(_, _)
 			| I64Load32S(_, _)
 			| I64Load32U(_, _) => {
-				// These instructions pop the address and pushes the result,
-				// which effictively don't modify the stack height.
 				stack.pop_values(1)?;
-				stack.push_values(1)?;
+				stack.push_type(ValueType::I64)?;
+			}
+			F32Load(_, _) => {
+				stack.pop_values(1)?;
+				stack.push_type(ValueType::F32)?;
+			}
+			F64Load(_, _) => {
+				stack.pop_values(1)?;
+				stack.push_type(ValueType::F64)?;
 			}
 
 			I32Store(_, _)
@@ -361,24 +450,115 @@ pub(crate) fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, E
 
 			CurrentMemory(_) => {
 				// Pushes current memory size
-				stack.push_values(1)?;
+				stack.push_type(ValueType::I32)?;
 			}
 			GrowMemory(_) => {
 				// Grow memory takes the value of pages to grow and pushes
 				stack.pop_values(1)?;
-				stack.push_values(1)?;
+				stack.push_type(ValueType::I32)?;
 			}
-
-			I32Const(_) | I64Const(_) | F32Const(_) | F64Const(_) => {
-				// These instructions just push the single literal value onto the stack.
-				stack.push_values(1)?;
+			#[cfg(feature = "bulk")]
+			Bulk(bulk) => {
+				use parity_wasm::elements::BulkInstruction::*;
+				match bulk {
+					// These take the destination, the source (or value) and the length.
+					MemoryInit(_) | MemoryCopy | MemoryFill | TableInit(_) | TableCopy => {
+						stack.pop_values(3)?;
+					}
+					MemoryDrop(_) | TableDrop(_) => {}
+				}
+			}
+			#[cfg(feature = "sign_ext")]
+			SignExt(sign_ext) => {
+				use parity_wasm::elements::SignExtInstruction::*;
+				// Sign-extension operators pop a value and push the extended result.
+				let value_type = match sign_ext {
+					I32Extend8S | I32Extend16S => ValueType::I32,
+					I64Extend8S | I64Extend16S | I64Extend32S => ValueType::I64,
+				};
+				stack.pop_values(1)?;
+				stack.push_type(value_type)?;
 			}
+			#[cfg(feature = "atomics")]
+			Atomics(atomic) => {
+				use parity_wasm::elements::AtomicsInstruction::*;
+				let (pop, push) = match atomic {
+					I32AtomicLoad(_) | I32AtomicLoad8u(_) | I32AtomicLoad16u(_) => {
+						(1, Some(ValueType::I32))
+					}
+					I64AtomicLoad(_) | I64AtomicLoad8u(_) | I64AtomicLoad16u(_) | I64AtomicLoad32u(_) => {
+						(1, Some(ValueType::I64))
+					}
+					I32AtomicStore(_) | I64AtomicStore(_) | I32AtomicStore8u(_) | I32AtomicStore16u(_) |
+					I64AtomicStore8u(_) | I64AtomicStore16u(_) | I64AtomicStore32u(_) => (2, None),
+					// Notify takes the address and the count of waiters to wake.
+					AtomicWake(_) => (2, Some(ValueType::I32)),
+					// Waits take the address, the expected value and the timeout.
+					I32AtomicWait(_) | I64AtomicWait(_) => (3, Some(ValueType::I32)),
+					I32AtomicRmwCmpxchg(_) | I32AtomicRmwCmpxchg8u(_) | I32AtomicRmwCmpxchg16u(_) => {
+						(3, Some(ValueType::I32))
+					}
+					I64AtomicRmwCmpxchg(_) | I64AtomicRmwCmpxchg8u(_) | I64AtomicRmwCmpxchg16u(_) |
+					I64AtomicRmwCmpxchg32u(_) => (3, Some(ValueType::I64)),
+					// All other read-modify-write operations take the address and the operand and
+					// push the previous value.
+					I32AtomicRmwAdd(_) | I32AtomicRmwAdd8u(_) | I32AtomicRmwAdd16u(_) |
+					I32AtomicRmwSub(_) | I32AtomicRmwSub8u(_) | I32AtomicRmwSub16u(_) |
+					I32AtomicRmwAnd(_) | I32AtomicRmwAnd8u(_) | I32AtomicRmwAnd16u(_) |
+					I32AtomicRmwOr(_) | I32AtomicRmwOr8u(_) | I32AtomicRmwOr16u(_) |
+					I32AtomicRmwXor(_) | I32AtomicRmwXor8u(_) | I32AtomicRmwXor16u(_) |
+					I32AtomicRmwXchg(_) | I32AtomicRmwXchg8u(_) | I32AtomicRmwXchg16u(_) => {
+						(2, Some(ValueType::I32))
+					}
+					_ => (2, Some(ValueType::I64)),
+				};
+				stack.pop_values(pop)?;
+				if let Some(value_type) = push {
+					stack.push_type(value_type)?;
+				}
+			}
+			#[cfg(feature = "simd")]
+			Simd(simd) => {
+				use parity_wasm::elements::SimdInstruction::*;
+				let (pop, push) = match simd {
+					V128Const(_) => (0, Some(ValueType::V128)),
+					V128Load(_) => (1, Some(ValueType::V128)),
+					V128Store(_) => (2, None),
+					I8x16Splat | I16x8Splat | I32x4Splat | I64x2Splat | F32x4Splat | F64x2Splat |
+					V128Not | I8x16Neg | I16x8Neg | I32x4Neg | I64x2Neg | F32x4Neg | F64x2Neg |
+					F32x4Abs | F64x2Abs | F32x4Sqrt | F64x2Sqrt | F32x4ConvertSI32x4 |
+					F32x4ConvertUI32x4 | F64x2ConvertSI64x2 | F64x2ConvertUI64x2 |
+					I32x4TruncSF32x4Sat | I32x4TruncUF32x4Sat | I64x2TruncSF64x2Sat |
+					I64x2TruncUF64x2Sat => (1, Some(ValueType::V128)),
+					I8x16ExtractLaneS(_) | I8x16ExtractLaneU(_) | I16x8ExtractLaneS(_) |
+					I16x8ExtractLaneU(_) | I32x4ExtractLane(_) => (1, Some(ValueType::I32)),
+					I64x2ExtractLane(_) => (1, Some(ValueType::I64)),
+					F32x4ExtractLane(_) => (1, Some(ValueType::F32)),
+					F64x2ExtractLane(_) => (1, Some(ValueType::F64)),
+					I8x16AnyTrue | I16x8AnyTrue | I32x4AnyTrue | I64x2AnyTrue | I8x16AllTrue |
+					I16x8AllTrue | I32x4AllTrue | I64x2AllTrue => (1, Some(ValueType::I32)),
+					V128Bitselect => (3, Some(ValueType::V128)),
+					// Lane replacements, shifts and binary operators take two operands and
+					// produce a vector.
+					_ => (2, Some(ValueType::V128)),
+				};
+				stack.pop_values(pop)?;
+				if let Some(value_type) = push {
+					stack.push_type(value_type)?;
+				}
+			}
+
+
+			I32Const(_) => stack.push_type(ValueType::I32)?,
+			I64Const(_) => stack.push_type(ValueType::I64)?,
+			F32Const(_) => stack.push_type(ValueType::F32)?,
+			F64Const(_) => stack.push_type(ValueType::F64)?,
 
 			I32Eqz | I64Eqz => {
 				// These instructions pop the value and compare it against zero, and pushes
 				// the result of the comparison.
 				stack.pop_values(1)?;
-				stack.push_values(1)?;
+				stack.push_type(ValueType::I32)?;
 			}
 
 			I32Eq | I32Ne | I32LtS | I32LtU | I32GtS | I32GtU | I32LeS | I32LeU | I32GeS
@@ -387,37 +567,67 @@ pub(crate) fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, E
 			| F64Lt | F64Gt | F64Le | F64Ge => {
 				// Comparison operations take two operands and produce one result.
 				stack.pop_values(2)?;
-				stack.push_values(1)?;
+				stack.push_type(ValueType::I32)?;
 			}
 
-			I32Clz | I32Ctz | I32Popcnt | I64Clz | I64Ctz | I64Popcnt | F32Abs | F32Neg
-			| F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt | F64Abs | F64Neg | F64Ceil
-			| F64Floor | F64Trunc | F64Nearest | F64Sqrt => {
+			I32Clz | I32Ctz | I32Popcnt => {
 				// Unary operators take one operand and produce one result.
 				stack.pop_values(1)?;
-				stack.push_values(1)?;
+				stack.push_type(ValueType::I32)?;
+			}
+			I64Clz | I64Ctz | I64Popcnt => {
+				stack.pop_values(1)?;
+				stack.push_type(ValueType::I64)?;
+			}
+			F32Abs | F32Neg | F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt => {
+				stack.pop_values(1)?;
+				stack.push_type(ValueType::F32)?;
+			}
+			F64Abs | F64Neg | F64Ceil | F64Floor | F64Trunc | F64Nearest | F64Sqrt => {
+				stack.pop_values(1)?;
+				stack.push_type(ValueType::F64)?;
 			}
 
 			I32Add | I32Sub | I32Mul | I32DivS | I32DivU | I32RemS | I32RemU | I32And | I32Or
-			| I32Xor | I32Shl | I32ShrS | I32ShrU | I32Rotl | I32Rotr | I64Add | I64Sub
-			| I64Mul | I64DivS | I64DivU | I64RemS | I64RemU | I64And | I64Or | I64Xor | I64Shl
-			| I64ShrS | I64ShrU | I64Rotl | I64Rotr | F32Add | F32Sub | F32Mul | F32Div
-			| F32Min | F32Max | F32Copysign | F64Add | F64Sub | F64Mul | F64Div | F64Min
-			| F64Max | F64Copysign => {
+			| I32Xor | I32Shl | I32ShrS | I32ShrU | I32Rotl | I32Rotr => {
 				// Binary operators take two operands and produce one result.
 				stack.pop_values(2)?;
-				stack.push_values(1)?;
+				stack.push_type(ValueType::I32)?;
+			}
+			I64Add | I64Sub | I64Mul | I64DivS | I64DivU | I64RemS | I64RemU | I64And | I64Or
+			| I64Xor | I64Shl | I64ShrS | I64ShrU | I64Rotl | I64Rotr => {
+				stack.pop_values(2)?;
+				stack.push_type(ValueType::I64)?;
+			}
+			F32Add | F32Sub | F32Mul | F32Div | F32Min | F32Max | F32Copysign => {
+				stack.pop_values(2)?;
+				stack.push_type(ValueType::F32)?;
+			}
+			F64Add | F64Sub | F64Mul | F64Div | F64Min | F64Max | F64Copysign => {
+				stack.pop_values(2)?;
+				stack.push_type(ValueType::F64)?;
 			}
 
 			I32WrapI64 | I32TruncSF32 | I32TruncUF32 | I32TruncSF64 | I32TruncUF64
-			| I64ExtendSI32 | I64ExtendUI32 | I64TruncSF32 | I64TruncUF32 | I64TruncSF64
-			| I64TruncUF64 | F32ConvertSI32 | F32ConvertUI32 | F32ConvertSI64 | F32ConvertUI64
-			| F32DemoteF64 | F64ConvertSI32 | F64ConvertUI32 | F64ConvertSI64 | F64ConvertUI64
-			| F64PromoteF32 | I32ReinterpretF32 | I64ReinterpretF64 | F32ReinterpretI32
-			| F64ReinterpretI64 => {
+			| I32ReinterpretF32 => {
 				// Conversion operators take one value and produce one result.
 				stack.pop_values(1)?;
-				stack.push_values(1)?;
+				stack.push_type(ValueType::I32)?;
+			}
+			I64ExtendSI32 | I64ExtendUI32 | I64TruncSF32 | I64TruncUF32 | I64TruncSF64
+			| I64TruncUF64 | I64ReinterpretF64 => {
+				stack.pop_values(1)?;
+				stack.push_type(ValueType::I64)?;
+			}
+			F32ConvertSI32 | F32ConvertUI32 | F32ConvertSI64 | F32ConvertUI64 | F32DemoteF64
+			| F32ReinterpretI32 => {
+				stack.pop_values(1)?;
+				stack.push_type(ValueType::F32)?;
+			}
+			F64ConvertSI32 | F64ConvertUI32 | F64ConvertSI64 | F64ConvertUI64 | F64PromoteF32
+			| F64ReinterpretI64 => {
+				stack.pop_values(1)?;
+				stack.push_type(ValueType::F64)?;
 			}
 		}
 		pc += 1;
@@ -454,7 +664,7 @@ mod tests {
 "#,
 		);
 
-		let height = compute(0, &module).unwrap();
+		let height = compute(0, &module, ValueSizes::uniform()).unwrap();
 		assert_eq!(height, 3);
 	}
 
@@ -471,7 +681,7 @@ mod tests {
 "#,
 		);
 
-		let height = compute(0, &module).unwrap();
+		let height = compute(0, &module, ValueSizes::uniform()).unwrap();
 		assert_eq!(height, 1);
 	}
 
@@ -489,7 +699,7 @@ mod tests {
 "#,
 		);
 
-		let height = compute(0, &module).unwrap();
+		let height = compute(0, &module, ValueSizes::uniform()).unwrap();
 		assert_eq!(height, 0);
 	}
 
@@ -522,7 +732,7 @@ mod tests {
 			.as_ref())
 			.expect("Failed to deserialize the module");
 
-		let height = compute(0, &module).unwrap();
+		let height = compute(0, &module, ValueSizes::uniform()).unwrap();
 		assert_eq!(height, 2);
 	}
 
@@ -546,7 +756,7 @@ mod tests {
 "#,
 		);
 
-		let height = compute(0, &module).unwrap();
+		let height = compute(0, &module, ValueSizes::uniform()).unwrap();
 		assert_eq!(height, 1);
 	}
 
@@ -568,7 +778,7 @@ mod tests {
 "#,
 		);
 
-		let height = compute(0, &module).unwrap();
+		let height = compute(0, &module, ValueSizes::uniform()).unwrap();
 		assert_eq!(height, 1);
 	}
 
@@ -594,7 +804,59 @@ mod tests {
 "#,
 		);
 
-		let height = compute(0, &module).unwrap();
+		let height = compute(0, &module, ValueSizes::uniform()).unwrap();
 		assert_eq!(height, 3);
 	}
+
+	#[cfg(feature = "sign_ext")]
+	#[test]
+	fn sign_extension() {
+		let mut features = wabt::Features::new();
+		features.enable_sign_extension();
+		let module = elements::deserialize_buffer(
+			&wabt::wat2wasm_with_features(
+				r#"
+(module
+	(func (result i32)
+		i32.const 1
+		i32.const 2
+		i32.extend8_s
+		i32.add
+	)
+)
+"#,
+				features,
+			).expect("Failed to wat2wasm"),
+		).expect("Failed to deserialize the module");
+
+		let height = compute(0, &module, ValueSizes::uniform()).unwrap();
+		assert_eq!(height, 2);
+	}
+
+	#[cfg(feature = "simd")]
+	#[test]
+	fn vectors_count_twice() {
+		use parity_wasm::builder;
+		use parity_wasm::elements::{Instruction::*, SimdInstruction};
+
+		let module = builder::module()
+			.function()
+				.signature().with_param(ValueType::I32).with_result(ValueType::I32).build()
+				.body()
+					.with_locals(vec![elements::Local::new(1, ValueType::V128)])
+					.with_instructions(elements::Instructions::new(vec![
+						GetLocal(1),
+						Simd(SimdInstruction::V128Const(Box::new([0; 16]))),
+						GetLocal(0),
+						Select,
+						Simd(SimdInstruction::I32x4ExtractLane(0)),
+						End,
+					]))
+					.build()
+				.build()
+			.build();
+
+		let height = compute(0, &module, ValueSizes::uniform()).unwrap();
+		assert_eq!(height, 5);
+	}
 }
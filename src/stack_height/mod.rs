@@ -32,12 +32,32 @@
 //! will increase before and decrease the stack height after the call to original function, and
 //! then make exported function and table entries, start section to point to a corresponding thunks.
 //!
+//! Modules with large tables get as many thunks as table entries. If the table can't change at
+//! runtime, `IndirectCalls::CostLookup` instead instruments the indirect calls themselves, looking
+//! up the stack cost of the called table entry in a single generated function.
+//!
+//! # Bounded modules
+//!
+//! If no function calls itself recursively, directly or through other functions, the highest
+//! stack height a module can reach is known statically. See `analyze` and
+//! `LimiterConfig::with_skip_bounded` for leaving modules which never exceed the limit untouched.
+//!
 //! # Stack cost
 //!
-//! Stack cost of the function is calculated as a sum of it's locals
-//! and the maximal height of the value stack.
+//! How the stack cost of a function is calculated depends on the `StackCostModel` passed to
+//! `inject_limiter`.
+//!
+//! `StackCostModel::LocalDeclarations` is the default of `LimiterConfig` and the model of earlier
+//! versions. It counts the local declarations of the function plus the maximal height of the
+//! value stack. A declaration of many locals counts only once and arguments aren't counted.
 //!
-//! All values are treated equally, as they have the same size.
+//! `StackCostModel::Sized` calculates the stack cost as a sum of the sizes of the function's
+//! arguments and locals and the maximal height of the value stack, that is the maximal sum of the
+//! sizes of the values on it. How large a value of each type is, is given by its `ValueSizes`.
+//!
+//! With `ValueSizes::uniform` all values are treated equally, as they have the same size. The only
+//! exception are the `v128` values of the SIMD proposal, which are twice as large and therefore
+//! count as two values.
 //!
 //! The rationale is that this makes it possible to use the following very naive wasm executor:
 //!
@@ -49,11 +69,13 @@
 //!   between the frames.
 //! - upon entry into the function entire stack frame is allocated.
 
+use crate::std::fmt;
 use crate::std::string::String;
 use crate::std::vec::Vec;
 
 use parity_wasm::elements::{self, Type};
 use parity_wasm::builder;
+use crate::remap::{IndexMapping, IndexRemap};
 
 /// Macro to generate preamble and postamble.
 macro_rules! instrument_call {
@@ -83,18 +105,33 @@ macro_rules! instrument_call {
 	}};
 }
 
+mod analysis;
+mod config;
+mod cost;
+mod indirect;
 mod max_height;
 mod thunk;
 
+pub use self::analysis::{analyze, FunctionStack, StackAnalysis};
+pub use self::config::{IndirectCalls, LimiterConfig};
+pub use self::cost::{StackCostModel, ValueSizes};
+
 /// Error that occured during processing the module.
 ///
 /// This means that the module is invalid.
 #[derive(Debug)]
 pub struct Error(String);
 
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		write!(f, "{}", self.0)
+	}
+}
+
 pub(crate) struct Context {
 	stack_height_global_idx: u32,
 	func_stack_costs: Vec<u32>,
+	elided_calls: Vec<bool>,
 	stack_limit: u32,
 }
 
@@ -109,37 +146,168 @@ impl Context {
 		self.func_stack_costs.get(func_idx as usize).cloned()
 	}
 
+	/// Returns whether calls to `func_idx` are left uninstrumented because their stack cost is
+	/// charged to the caller.
+	fn is_elided(&self, func_idx: u32) -> bool {
+		self.elided_calls.get(func_idx as usize).cloned().unwrap_or(false)
+	}
+
 	/// Returns stack limit specified by the rules.
 	fn stack_limit(&self) -> u32 {
 		self.stack_limit
 	}
 }
 
+/// Where the global variable tracking the stack height comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackHeightGlobal<'a> {
+	/// Add a mutable i32 global which can't be accessed from outside of the module.
+	///
+	/// This is what `inject_limiter` does.
+	Internal,
+	/// Add a mutable i32 global and export it under the specified name.
+	///
+	/// This allows the embedder to read the stack height and to reset it after a trap.
+	Exported(&'a str),
+	/// Import a mutable i32 global with the specified module and field name.
+	///
+	/// The embedder has to provide a global initialized to zero. The import is added after all
+	/// other global imports, so all references to globals defined by the module are shifted by one.
+	Imported {
+		module: &'a str,
+		field: &'a str,
+	},
+}
+
 /// Instrument a module with stack height limiter.
 ///
 /// See module-level documentation for more details.
 ///
 /// # Errors
 ///
-/// Returns `Err` if module is invalid and can't be
+/// Returns `Err` if module is invalid and can't be instrumented, e.g. because a stack cost
+/// can't be computed.
 pub fn inject_limiter(
-	mut module: elements::Module,
+	module: elements::Module,
 	stack_limit: u32,
+	model: StackCostModel,
 ) -> Result<elements::Module, Error> {
+	inject_limiter_with_global(module, stack_limit, model, StackHeightGlobal::Internal)
+}
+
+/// Instrument a module with stack height limiter, tracking the stack height in the specified
+/// `global`.
+///
+/// Fails under the same conditions as `inject_limiter` and if the module already exports
+/// something under the name of an exported `global`.
+pub fn inject_limiter_with_global(
+	module: elements::Module,
+	stack_limit: u32,
+	model: StackCostModel,
+	global: StackHeightGlobal,
+) -> Result<elements::Module, Error> {
+	let config = LimiterConfig::new(stack_limit)
+		.with_cost_model(model)
+		.with_global(global);
+	inject_limiter_with_config(module, &config)
+}
+
+/// Instrument a module with stack height limiter as described by `config`.
+///
+/// Fails under the same conditions as `inject_limiter_with_global`.
+pub fn inject_limiter_with_config(
+	mut module: elements::Module,
+	config: &LimiterConfig,
+) -> Result<elements::Module, Error> {
+	// `analyze` assumes imported functions don't call back into the module, which would
+	// start over from the stack height at the call if the module weren't instrumented.
+	let has_func_imports = module.import_count(elements::ImportCountType::Function) != 0;
+	if config.skip_bounded() && !has_func_imports {
+		let max_depth = analysis::analyze_with_extra_cost(
+			&module,
+			config.cost_model(),
+			config.extra_stack_cost(),
+		)?.max_depth();
+		if matches!(max_depth, Some(depth) if depth <= config.stack_limit() as u64) {
+			if config.global() != StackHeightGlobal::Internal {
+				generate_stack_height_global(&mut module, config.global())?;
+			}
+			return Ok(module);
+		}
+	}
+
+	let (func_stack_costs, elided_calls) = if config.elide_bounded_calls() {
+		compute_elided_stack_costs(&module, config.cost_model(), config.extra_stack_cost())?
+	} else {
+		let stack_costs = compute_stack_costs(&module, config.cost_model())?;
+		(with_extra_cost(&module, stack_costs, config.extra_stack_cost())?, Vec::new())
+	};
+
 	let mut ctx = Context {
-		stack_height_global_idx: generate_stack_height_global(&mut module),
-		func_stack_costs: compute_stack_costs(&module)?,
-		stack_limit,
+		stack_height_global_idx: generate_stack_height_global(&mut module, config.global())?,
+		func_stack_costs,
+		elided_calls,
+		stack_limit: config.stack_limit(),
 	};
 
 	instrument_functions(&mut ctx, &mut module)?;
-	let module = thunk::generate_thunks(&mut ctx, module)?;
+	let table = match config.indirect_calls() {
+		IndirectCalls::Thunks => None,
+		IndirectCalls::CostLookup => indirect::static_table(&module),
+	};
+	let module = match table {
+		Some(table) => {
+			let module = indirect::instrument_indirect_calls(&ctx, module, &table)?;
+			thunk::generate_thunks(&mut ctx, module, false)?
+		},
+		None => thunk::generate_thunks(&mut ctx, module, true)?,
+	};
 
 	Ok(module)
 }
 
-/// Generate a new global that will be used for tracking current stack height.
-fn generate_stack_height_global(module: &mut elements::Module) -> u32 {
+/// Generate the global that will be used for tracking current stack height.
+///
+/// Returns its index in the global index space.
+fn generate_stack_height_global(
+	module: &mut elements::Module,
+	global: StackHeightGlobal,
+) -> Result<u32, Error> {
+	match global {
+		StackHeightGlobal::Internal => Ok(add_stack_height_global(module)),
+		StackHeightGlobal::Exported(name) => {
+			let exported = module
+				.export_section()
+				.into_iter()
+				.flat_map(|section| section.entries())
+				.any(|entry| entry.field() == name);
+			if exported {
+				return Err(Error(format!("Export {} already exists", name)));
+			}
+
+			let global_idx = add_stack_height_global(module);
+			let export_entry = elements::ExportEntry::new(
+				name.into(),
+				elements::Internal::Global(global_idx),
+			);
+			match module.export_section_mut() {
+				Some(section) => section.entries_mut().push(export_entry),
+				None => module
+					.insert_section(elements::Section::Export(
+						elements::ExportSection::with_entries(vec![export_entry]),
+					))
+					.expect("export section doesn't exist; qed"),
+			}
+			Ok(global_idx)
+		},
+		StackHeightGlobal::Imported { module: import_module, field } => {
+			Ok(import_stack_height_global(module, import_module, field))
+		},
+	}
+}
+
+/// Add a new mutable global to the global section and return its index.
+fn add_stack_height_global(module: &mut elements::Module) -> u32 {
 	let global_entry = builder::global()
 		.value_type()
 		.i32()
@@ -147,25 +315,55 @@ fn generate_stack_height_global(module: &mut elements::Module) -> u32 {
 		.init_expr(elements::Instruction::I32Const(0))
 		.build();
 
+	let imported_globals = module.import_count(elements::ImportCountType::Global) as u32;
+
 	// Try to find an existing global section.
-	for section in module.sections_mut() {
-		if let elements::Section::Global(gs) = section {
-			gs.entries_mut().push(global_entry);
-			return (gs.entries().len() as u32) - 1;
-		}
+	if let Some(gs) = module.global_section_mut() {
+		gs.entries_mut().push(global_entry);
+		return imported_globals + (gs.entries().len() as u32) - 1;
 	}
 
 	// Existing section not found, create one!
-	module.sections_mut().push(elements::Section::Global(
-		elements::GlobalSection::with_entries(vec![global_entry]),
-	));
-	0
+	module
+		.insert_section(elements::Section::Global(
+			elements::GlobalSection::with_entries(vec![global_entry]),
+		))
+		.expect("global section doesn't exist; qed");
+	imported_globals
+}
+
+/// Add an import of a mutable global after all other global imports and return its index.
+///
+/// All references to defined globals are shifted by one.
+fn import_stack_height_global(module: &mut elements::Module, import_module: &str, field: &str) -> u32 {
+	let global_idx = module.import_count(elements::ImportCountType::Global) as u32;
+
+	let import_entry = elements::ImportEntry::new(
+		import_module.into(),
+		field.into(),
+		elements::External::Global(elements::GlobalType::new(elements::ValueType::I32, true)),
+	);
+	match module.import_section_mut() {
+		Some(section) => section.entries_mut().push(import_entry),
+		None => module
+			.insert_section(elements::Section::Import(
+				elements::ImportSection::with_entries(vec![import_entry]),
+			))
+			.expect("import section doesn't exist; qed"),
+	}
+
+	IndexRemap::new()
+		.with_globals(IndexMapping::Inserted { index: global_idx, count: 1 })
+		.apply(module);
+
+	global_idx
 }
 
-/// Calculate stack costs for all functions.
+/// Calculate stack costs for all functions according to `model`.
 ///
-/// Returns a vector with a stack cost for each function, including imports.
-fn compute_stack_costs(module: &elements::Module) -> Result<Vec<u32>, Error> {
+/// Returns a vector with a stack cost for each function, including imports, whose stack cost
+/// is zero. These are the costs `inject_limiter` charges for calling the respective function.
+pub fn compute_stack_costs(module: &elements::Module, model: StackCostModel) -> Result<Vec<u32>, Error> {
 	let func_imports = module.import_count(elements::ImportCountType::Function);
 
 	// TODO: optimize!
@@ -175,16 +373,112 @@ fn compute_stack_costs(module: &elements::Module) -> Result<Vec<u32>, Error> {
 				// We can't calculate stack_cost of the import functions.
 				Ok(0)
 			} else {
-				compute_stack_cost(func_idx as u32, &module)
+				compute_stack_cost(func_idx as u32, module, model)
 			}
 		})
 		.collect()
 }
 
-/// Stack cost of the given *defined* function is the sum of it's locals count (that is,
-/// number of arguments plus number of local variables) and the maximal stack
-/// height.
-fn compute_stack_cost(func_idx: u32, module: &elements::Module) -> Result<u32, Error> {
+/// Adds `extra_cost` to the `stack_costs` of all defined functions.
+fn with_extra_cost(
+	module: &elements::Module,
+	mut stack_costs: Vec<u32>,
+	extra_cost: u32,
+) -> Result<Vec<u32>, Error> {
+	let func_imports = module.import_count(elements::ImportCountType::Function);
+	for (func_idx, stack_cost) in stack_costs.iter_mut().enumerate().skip(func_imports) {
+		// The cost is added to an `i32` stack height global.
+		*stack_cost = stack_cost
+			.checked_add(extra_cost)
+			.filter(|cost| *cost <= i32::MAX as u32)
+			.ok_or_else(|| Error(format!("Stack cost of function {} overflows", func_idx)))?;
+	}
+	Ok(stack_costs)
+}
+
+/// Calculate stack costs for all functions with calls to functions with a bounded call tree
+/// being charged to the caller.
+///
+/// The stack cost of a function with a bounded call tree is the highest sum of stack costs along
+/// its chains of direct calls, as none of these are instrumented. Its indirect calls are charged
+/// at the call site like any other, so the functions of the table aren't included. Other
+/// functions add the highest stack cost of the bounded functions they call directly to their own.
+/// Returns the stack costs along with whether calls to the respective function are elided.
+fn compute_elided_stack_costs(
+	module: &elements::Module,
+	model: StackCostModel,
+	extra_cost: u32,
+) -> Result<(Vec<u32>, Vec<bool>), Error> {
+	let analysis = analysis::analyze_with_extra_cost(module, model, extra_cost)?;
+	let functions = analysis.functions();
+	let elided_calls: Vec<bool> = functions
+		.iter()
+		.map(|function| function.max_depth().is_some())
+		.collect();
+
+	// All direct callees of a bounded function are bounded, so they can be visited depth first
+	// without running into a cycle.
+	let mut direct_depths: Vec<Option<u64>> = vec![None; functions.len()];
+	for root in (0..functions.len()).filter(|func_idx| elided_calls[*func_idx]) {
+		let mut stack = vec![root];
+		while let Some(&func_idx) = stack.last() {
+			if direct_depths[func_idx].is_some() {
+				stack.pop();
+				continue;
+			}
+			let function = &functions[func_idx];
+			let pending = function
+				.callees()
+				.iter()
+				.map(|callee| *callee as usize)
+				.filter(|callee| direct_depths[*callee].is_none())
+				.collect::<Vec<_>>();
+			if pending.is_empty() {
+				let callees_depth = function
+					.callees()
+					.iter()
+					.filter_map(|callee| direct_depths[*callee as usize])
+					.max()
+					.unwrap_or(0);
+				direct_depths[func_idx] = Some(function.stack_cost() as u64 + callees_depth);
+				stack.pop();
+			} else {
+				stack.extend(pending);
+			}
+		}
+	}
+
+	let stack_costs = functions
+		.iter()
+		.zip(&direct_depths)
+		.map(|(function, direct_depth)| {
+			let cost = direct_depth.unwrap_or_else(|| {
+				let elided_cost = function
+					.callees()
+					.iter()
+					.filter_map(|callee| direct_depths[*callee as usize])
+					.max()
+					.unwrap_or(0);
+				function.stack_cost() as u64 + elided_cost
+			});
+			// The cost is added to an `i32` stack height global.
+			if cost > i32::MAX as u64 {
+				return Err(Error(format!("Stack cost of function {} overflows", function.func_idx())));
+			}
+			Ok(cost as u32)
+		})
+		.collect::<Result<Vec<_>, Error>>()?;
+
+	Ok((stack_costs, elided_calls))
+}
+
+/// Stack cost of the given *defined* function is the sum of the cost of it's locals (that is,
+/// arguments plus local variables) and the maximal stack height, as defined by `model`.
+fn compute_stack_cost(
+	func_idx: u32,
+	module: &elements::Module,
+	model: StackCostModel,
+) -> Result<u32, Error> {
 	// To calculate the cost of a function we need to convert index from
 	// function index space to defined function spaces.
 	let func_imports = module.import_count(elements::ImportCountType::Function) as u32;
@@ -199,15 +493,37 @@ fn compute_stack_cost(func_idx: u32, module: &elements::Module) -> Result<u32, E
 		.bodies()
 		.get(defined_func_idx as usize)
 		.ok_or_else(|| Error("Function body is out of bounds".into()))?;
-	let locals_count = body.locals().len() as u32;
+
+	let (locals_cost, sizes) = match model {
+		StackCostModel::LocalDeclarations => (body.locals().len() as u64, ValueSizes::uniform()),
+		StackCostModel::Sized(sizes) => {
+			let params_cost: u64 = resolve_func_type(func_idx, module)?
+				.params()
+				.iter()
+				.map(|param| sizes.size_of(*param) as u64)
+				.sum();
+			let locals_cost: u64 = body
+				.locals()
+				.iter()
+				.map(|local| local.count() as u64 * sizes.size_of(local.value_type()) as u64)
+				.sum();
+			(params_cost + locals_cost, sizes)
+		}
+	};
 
 	let max_stack_height =
 		max_height::compute(
 			defined_func_idx,
-			module
+			module,
+			sizes,
 		)?;
 
-	Ok(locals_count + max_stack_height)
+	// The cost is added to an `i32` stack height global.
+	let cost = locals_cost + max_stack_height as u64;
+	if cost > i32::MAX as u64 {
+		return Err(Error(format!("Stack cost of function {} overflows", func_idx)));
+	}
+	Ok(cost as u32)
 }
 
 fn instrument_functions(ctx: &mut Context, module: &mut elements::Module) -> Result<(), Error> {
@@ -281,8 +597,8 @@ fn instrument_function(
 						)?;
 
 					// Instrument only calls to a functions which stack_cost is
-					// non-zero.
-					if callee_stack_cost > 0 {
+					// non-zero and isn't charged to the caller.
+					if callee_stack_cost > 0 && !ctx.is_elided(*callee_idx) {
 						Action::InstrumentCall {
 							callee_idx: *callee_idx,
 							callee_stack_cost,
@@ -405,8 +721,308 @@ mod tests {
 "#,
 		);
 
-		let module = inject_limiter(module, 1024)
+		let module = inject_limiter(module, 1024, StackCostModel::LocalDeclarations)
+			.expect("Failed to inject stack counter");
+		validate_module(module);
+	}
+
+	#[test]
+	fn local_declarations_count_once() {
+		let module = parse_wat(
+			r#"
+(module
+	(func (param i32 i64)
+		(local i32 i32 i32) (local f64)
+		get_local 0
+		drop
+	)
+)
+"#,
+		);
+
+		let cost = compute_stack_cost(0, &module, StackCostModel::LocalDeclarations)
+			.expect("Failed to compute stack cost");
+		assert_eq!(cost, 2 + 1);
+	}
+
+	#[test]
+	fn sized_counts_params_and_locals() {
+		let module = parse_wat(
+			r#"
+(module
+	(func (param i32 i64)
+		(local i32 i32 i32) (local f64)
+		get_local 1
+		get_local 0
+		drop
+		drop
+	)
+)
+"#,
+		);
+
+		let uniform = compute_stack_cost(0, &module, StackCostModel::Sized(ValueSizes::uniform()))
+			.expect("Failed to compute stack cost");
+		assert_eq!(uniform, 2 + 4 + 2);
+
+		let bytes = compute_stack_cost(0, &module, StackCostModel::Sized(ValueSizes::bytes()))
+			.expect("Failed to compute stack cost");
+		assert_eq!(bytes, (4 + 8) + (3 * 4 + 8) + (8 + 4));
+
+		let sizes = ValueSizes::uniform().with_size(elements::ValueType::I64, 3);
+		let weighted = compute_stack_cost(0, &module, StackCostModel::Sized(sizes))
+			.expect("Failed to compute stack cost");
+		assert_eq!(weighted, (1 + 3) + 4 + (3 + 1));
+	}
+
+	#[test]
+	fn exported_global() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "g" (global i32))
+	(func (export "f") (result i32)
+		get_global 0
+	)
+)
+"#,
+		);
+
+		let module = inject_limiter_with_global(
+			module,
+			1024,
+			StackCostModel::LocalDeclarations,
+			StackHeightGlobal::Exported("stack_height"),
+		).expect("Failed to inject stack counter");
+
+		let exports = module.export_section().expect("Export section was added").entries();
+		assert!(exports.iter().any(|entry|
+			entry.field() == "stack_height" && *entry.internal() == elements::Internal::Global(1)
+		));
+		validate_module(module);
+	}
+
+	#[test]
+	fn exported_global_name_taken() {
+		let module = parse_wat(
+			r#"
+(module
+	(func (export "f"))
+)
+"#,
+		);
+
+		let result = inject_limiter_with_global(
+			module,
+			1024,
+			StackCostModel::LocalDeclarations,
+			StackHeightGlobal::Exported("f"),
+		);
+		assert!(result.is_err());
+	}
+
+	#[test]
+	fn imported_global() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "g" (global i32))
+	(global $defined (mut i32) (get_global 0))
+	(func $f
+		get_global $defined
+		set_global $defined
+	)
+	(func (export "call")
+		call $f
+	)
+	(export "defined" (global $defined))
+)
+"#,
+		);
+
+		let module = inject_limiter_with_global(
+			module,
+			1024,
+			StackCostModel::LocalDeclarations,
+			StackHeightGlobal::Imported { module: "env", field: "stack_height" },
+		).expect("Failed to inject stack counter");
+
+		let imports = module.import_section().expect("Import section exists").entries();
+		assert_eq!(imports[1].module(), "env");
+		assert_eq!(imports[1].field(), "stack_height");
+
+		let code = module.code_section().expect("Code section exists").bodies()[0].code().elements();
+		assert_eq!(
+			code[..2],
+			[elements::Instruction::GetGlobal(2), elements::Instruction::SetGlobal(2)],
+		);
+		// The stack height is tracked in the imported global.
+		let call = module.code_section().unwrap().bodies()[1].code().elements();
+		assert_eq!(call[0], elements::Instruction::GetGlobal(1));
+
+		let exports = module.export_section().expect("Export section exists").entries();
+		assert!(exports.iter().any(|entry|
+			entry.field() == "defined" && *entry.internal() == elements::Internal::Global(2)
+		));
+		validate_module(module);
+	}
+
+	#[test]
+	fn skip_bounded() {
+		let source = r#"
+(module
+	(func $leaf (result i32)
+		i32.const 1
+	)
+	(func (export "main") (result i32)
+		call $leaf
+	)
+)
+"#;
+		let config = LimiterConfig::new(2).with_skip_bounded(true);
+
+		let module = inject_limiter_with_config(parse_wat(source), &config)
+			.expect("Failed to inject stack counter");
+		assert_eq!(module, parse_wat(source));
+
+		let config = config.with_global(StackHeightGlobal::Exported("sh"));
+		let module = inject_limiter_with_config(parse_wat(source), &config)
+			.expect("Failed to inject stack counter");
+		assert_eq!(module.functions_space(), 2);
+		assert_eq!(module.export_section().expect("Export section exists").entries().len(), 2);
+
+		// The depth of 2 exceeds a limit of 1.
+		let config = LimiterConfig::new(1).with_skip_bounded(true);
+		let module = inject_limiter_with_config(parse_wat(source), &config)
+			.expect("Failed to inject stack counter");
+		assert_eq!(module.functions_space(), 3);
+		validate_module(module);
+	}
+
+	#[test]
+	fn skip_bounded_with_callback() {
+		// The import may call back into the export, recursing without a bound.
+		let source = r#"
+(module
+	(import "env" "callback" (func $callback))
+	(func (export "main") (result i32)
+		call $callback
+		i32.const 1
+	)
+)
+"#;
+		let config = LimiterConfig::new(1024).with_skip_bounded(true);
+
+		let module = inject_limiter_with_config(parse_wat(source), &config)
+			.expect("Failed to inject stack counter");
+		assert_ne!(module, parse_wat(source));
+		// The export points to a thunk charging the stack height.
+		let exports = module.export_section().expect("Export section exists").entries();
+		assert_eq!(*exports[0].internal(), elements::Internal::Function(2));
+		validate_module(module);
+	}
+
+	#[test]
+	fn elide_bounded_calls() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = parse_wat(
+			r#"
+(module
+	(func $leaf (result i32)
+		i32.const 1
+		i32.const 2
+		i32.add
+	)
+	(func $middle (result i32)
+		call $leaf
+	)
+	(func $recursive (export "recursive") (param i32) (result i32)
+		call $middle
+		get_local 0
+		br_if 0
+		drop
+		i32.const 0
+		call $recursive
+	)
+)
+"#,
+		);
+
+		let config = LimiterConfig::new(1024).with_elide_bounded_calls(true);
+		let module = inject_limiter_with_config(module, &config)
 			.expect("Failed to inject stack counter");
+
+		let bodies = module.code_section().expect("Code section exists").bodies();
+		// Bounded calls are left untouched.
+		assert_eq!(bodies[1].code().elements(), &[Call(0), End]);
+		// The recursive call is charged its own stack cost of 2 plus the 3 of `$middle`.
+		let recursive = bodies[2].code().elements();
+		assert_eq!(recursive[0], Call(1));
+		assert_eq!(&recursive[5..9], &[GetGlobal(0), I32Const(5), I32Add, SetGlobal(0)]);
+		validate_module(module);
+	}
+
+	#[test]
+	fn elide_bounded_calls_with_call_indirect() {
+		use parity_wasm::elements::Instruction::*;
+
+		let source = r#"
+(module
+	(type $t (func (result i32)))
+	(table 1 anyfunc)
+	(elem (i32.const 0) $target)
+	(func $leaf (result i32)
+		i32.const 1
+		i32.const 2
+		i32.add
+	)
+	(func $target (type $t)
+		(local i32) (local i64) (local f32)
+		i32.const 0
+	)
+	(func $caller (export "caller") (result i32)
+		call $leaf
+		drop
+		i32.const 0
+		call_indirect (type $t)
+	)
+)
+"#;
+		let thunk_cost = |module: &elements::Module, func_idx: u32| {
+			let func_imports = module.import_count(elements::ImportCountType::Function) as u32;
+			let body = &module.code_section().unwrap().bodies()[(func_idx - func_imports) as usize];
+			body.code().elements()[1].clone()
+		};
+
+		// `$caller` is charged its own stack cost of 1 plus the 2 of `$leaf`, but not the 4 of
+		// `$target`, which is charged when calling it through the table.
+		let config = LimiterConfig::new(1024).with_elide_bounded_calls(true);
+		let module = inject_limiter_with_config(parse_wat(source), &config)
+			.expect("Failed to inject stack counter");
+		let exports = module.export_section().expect("Export section exists").entries();
+		let caller_thunk = match exports[0].internal() {
+			elements::Internal::Function(func_idx) => *func_idx,
+			_ => panic!("Export is a function"),
+		};
+		assert_eq!(thunk_cost(&module, caller_thunk), I32Const(3));
+		let segments = module.elements_section().expect("Elements section exists").entries();
+		assert_eq!(thunk_cost(&module, segments[0].members()[0]), I32Const(4));
+		validate_module(module);
+
+		let config = config.with_indirect_calls(IndirectCalls::CostLookup);
+		let module = inject_limiter_with_config(parse_wat(source), &config)
+			.expect("Failed to inject stack counter");
+		let exports = module.export_section().expect("Export section exists").entries();
+		let caller_thunk = match exports[0].internal() {
+			elements::Internal::Function(func_idx) => *func_idx,
+			_ => panic!("Export is a function"),
+		};
+		assert_eq!(thunk_cost(&module, caller_thunk), I32Const(3));
+		// The call site looks up the cost of `$target`.
+		let caller = module.code_section().unwrap().bodies()[2].code().elements();
+		assert!(caller.iter().any(|instruction| matches!(instruction, CallIndirect(..))));
+		assert!(caller.contains(&Call(3)));
 		validate_module(module);
 	}
 }
@@ -16,9 +16,12 @@ struct Thunk {
 	callee_stack_cost: u32,
 }
 
+/// Generates thunks for the exported functions, the start function and, if `table_thunks` is
+/// set, the functions of the table.
 pub(crate) fn generate_thunks(
 	ctx: &mut Context,
 	module: elements::Module,
+	table_thunks: bool,
 ) -> Result<elements::Module, Error> {
 	// First, we need to collect all function indices that should be replaced by thunks
 
@@ -40,6 +43,7 @@ pub(crate) fn generate_thunks(
 		});
 		let table_func_indices = elem_segments
 			.iter()
+			.filter(|_| table_thunks)
 			.flat_map(|segment| segment.members())
 			.cloned();
 
@@ -135,7 +139,7 @@ pub(crate) fn generate_thunks(
 					}
 				}
 			}
-			elements::Section::Element(elem_section) => {
+			elements::Section::Element(elem_section) if table_thunks => {
 				for segment in elem_section.entries_mut() {
 					for function_idx in segment.members_mut() {
 						fixup(function_idx)
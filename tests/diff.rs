@@ -81,11 +81,14 @@ mod stack_height {
 
 	macro_rules! def_stack_height_test {
 		( $name:ident ) => {
+			def_stack_height_test!($name, utils::stack_height::LimiterConfig::new(1024));
+		};
+		( $name:ident, $config:expr ) => {
 			#[test]
 			fn $name() {
 				run_diff_test("stack-height", concat!(stringify!($name), ".wat"), |input| {
 					let module = elements::deserialize_buffer(input).expect("Failed to deserialize");
-					let instrumented = utils::stack_height::inject_limiter(module, 1024).expect("Failed to instrument with stack counter");
+					let instrumented = utils::stack_height::inject_limiter_with_config(module, &$config).expect("Failed to instrument with stack counter");
 					elements::serialize(instrumented).expect("Failed to serialize")
 				});
 			}
@@ -97,6 +100,11 @@ mod stack_height {
 	def_stack_height_test!(table);
 	def_stack_height_test!(global);
 	def_stack_height_test!(imports);
+	def_stack_height_test!(
+		table_lookup,
+		utils::stack_height::LimiterConfig::new(1024)
+			.with_indirect_calls(utils::stack_height::IndirectCalls::CostLookup)
+	);
 }
 
 mod gas {
@@ -104,16 +112,46 @@ mod gas {
 
 	macro_rules! def_gas_test {
 		( $name:ident ) => {
-			#[test]
-			fn $name() {
-				run_diff_test("gas", concat!(stringify!($name), ".wat"), |input| {
-					let rules = utils::rules::Set::default();
-
-					let module = elements::deserialize_buffer(input).expect("Failed to deserialize");
+			def_gas_test!($name, utils::rules::Set::default());
+		};
+		( $name:ident, $rules:expr ) => {
+			mod $name {
+				use super::*;
+
+				#[test]
+				fn inject() {
+					run_diff_test("gas", concat!(stringify!($name), ".wat"), |input| {
+						let rules = $rules;
+
+						let module = elements::deserialize_buffer(input).expect("Failed to deserialize");
+						let instrumented = utils::inject_gas_counter(module, &rules, "env")
+							.expect("Failed to instrument with gas metering");
+						elements::serialize(instrumented).expect("Failed to serialize")
+					});
+				}
+
+				#[test]
+				fn strip_round_trip() {
+					let fixture_path = concat!(
+						env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/gas/", stringify!($name), ".wat",
+					);
+					let fixture_wat = slurp(fixture_path).expect("Failed to read fixture");
+					let fixture_wasm = wabt::wat2wasm(fixture_wat).expect("Failed to read fixture");
+					let rules = $rules;
+					let config = utils::GasInjectionConfig::new("env");
+
+					let module = elements::deserialize_buffer(&fixture_wasm).expect("Failed to deserialize");
 					let instrumented = utils::inject_gas_counter(module, &rules, "env")
 						.expect("Failed to instrument with gas metering");
-					elements::serialize(instrumented).expect("Failed to serialize")
-				});
+					let stripped = utils::strip_gas_counter(instrumented, &config)
+						.expect("Failed to strip gas metering");
+					let stripped_wasm = elements::serialize(stripped).expect("Failed to serialize");
+
+					assert_eq!(
+						wabt::wasm2wat(&stripped_wasm).expect("Failed to convert result wasm to wat"),
+						wabt::wasm2wat(&fixture_wasm).expect("Failed to convert fixture wasm to wat"),
+					);
+				}
 			}
 		};
 	}
@@ -124,4 +162,48 @@ mod gas {
 	def_gas_test!(start);
 	def_gas_test!(call);
 	def_gas_test!(branch);
+	def_gas_test!(grow, utils::rules::Set::default().with_grow_cost(10000));
+	def_gas_test!(grow_overflow, utils::rules::Set::default().with_grow_cost(u32::MAX));
+}
+
+mod instrument {
+	use super::*;
+
+	macro_rules! def_instrument_test {
+		( $name:ident ) => {
+			def_instrument_test!($name, utils::rules::Set::default());
+		};
+		( $name:ident, $rules:expr ) => {
+			def_instrument_test!(
+				$name,
+				$rules,
+				utils::GasBackend::ImportedFunction(&utils::GasInjectionConfig::new("env"))
+			);
+		};
+		( $name:ident, $rules:expr, $backend:expr ) => {
+			#[test]
+			fn $name() {
+				run_diff_test("instrument", concat!(stringify!($name), ".wat"), |input| {
+					let rules = $rules;
+
+					let module = elements::deserialize_buffer(input).expect("Failed to deserialize");
+					let instrumented = utils::instrument_with_config(
+						module,
+						&rules,
+						$backend,
+						&utils::stack_height::LimiterConfig::new(1024),
+					).expect("Failed to instrument");
+					elements::serialize(instrumented).expect("Failed to serialize")
+				});
+			}
+		};
+	}
+
+	def_instrument_test!(thunks);
+	def_instrument_test!(grow, utils::rules::Set::default().with_grow_cost(10000));
+	def_instrument_test!(
+		grow_global,
+		utils::rules::Set::default().with_grow_cost(10000),
+		utils::GasBackend::MutableGlobal("gas_left")
+	);
 }
@@ -0,0 +1,29 @@
+(module
+  (type (;0;) (func (result i32)))
+  (type (;1;) (func (param i32)))
+  (type (;2;) (func (param i32) (result i32)))
+  (import "env" "gas" (func (;0;) (type 1)))
+  (func (;1;) (type 0) (result i32)
+    i32.const 15
+    call 0
+    i32.const 65536
+    call 2)
+  (func (;2;) (type 2) (param i32) (result i32)
+    (local i64)
+    local.get 0
+    i64.extend_i32_u
+    i64.const 4294967295
+    i64.mul
+    local.tee 1
+    i64.const 2147483647
+    i64.gt_u
+    if  ;; label = @1
+      unreachable
+    end
+    local.get 1
+    i32.wrap_i64
+    call 0
+    local.get 0
+    memory.grow)
+  (memory (;0;) 1)
+  (export "grow_a_lot" (func 1)))
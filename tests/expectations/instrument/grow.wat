@@ -0,0 +1,93 @@
+(module
+  (type (;0;) (func (param i32) (result i32)))
+  (type (;1;) (func (result i32)))
+  (type (;2;) (func (param i32)))
+  (import "env" "gas" (func (;0;) (type 2)))
+  (func (;1;) (type 0) (param i32) (result i32)
+    i32.const 15
+    call 0
+    local.get 0
+    call 5)
+  (func (;2;) (type 1) (result i32)
+    i32.const 14
+    call 0
+    i32.const 1
+    global.get 0
+    i32.const 5
+    i32.add
+    global.set 0
+    global.get 0
+    i32.const 1024
+    i32.gt_u
+    if  ;; label = @1
+      i32.const 1
+      call 0
+      unreachable
+    end
+    call 1
+    global.get 0
+    i32.const 5
+    i32.sub
+    global.set 0)
+  (func (;3;) (type 0) (param i32) (result i32)
+    i32.const 14
+    call 0
+    local.get 0
+    global.get 0
+    i32.const 5
+    i32.add
+    global.set 0
+    global.get 0
+    i32.const 1024
+    i32.gt_u
+    if  ;; label = @1
+      i32.const 1
+      call 0
+      unreachable
+    end
+    call 1
+    global.get 0
+    i32.const 5
+    i32.sub
+    global.set 0)
+  (func (;4;) (type 1) (result i32)
+    i32.const 13
+    call 0
+    global.get 0
+    i32.const 5
+    i32.add
+    global.set 0
+    global.get 0
+    i32.const 1024
+    i32.gt_u
+    if  ;; label = @1
+      i32.const 1
+      call 0
+      unreachable
+    end
+    call 2
+    global.get 0
+    i32.const 5
+    i32.sub
+    global.set 0)
+  (func (;5;) (type 0) (param i32) (result i32)
+    (local i64)
+    local.get 0
+    i64.extend_i32_u
+    i64.const 10000
+    i64.mul
+    local.tee 1
+    i64.const 2147483647
+    i64.gt_u
+    if  ;; label = @1
+      unreachable
+    end
+    local.get 1
+    i32.wrap_i64
+    call 0
+    local.get 0
+    memory.grow)
+  (memory (;0;) 0 1)
+  (global (;0;) (mut i32) (i32.const 0))
+  (export "grow" (func 3))
+  (export "call" (func 4)))
@@ -0,0 +1,150 @@
+(module
+  (type (;0;) (func (param i32) (result i32)))
+  (type (;1;) (func (result i32)))
+  (func (;0;) (type 0) (param i32) (result i32)
+    global.get 1
+    i64.const 16
+    i64.lt_u
+    if  ;; label = @1
+      unreachable
+    end
+    global.get 1
+    i64.const 16
+    i64.sub
+    global.set 1
+    local.get 0
+    call 4)
+  (func (;1;) (type 1) (result i32)
+    global.get 1
+    i64.const 14
+    i64.lt_u
+    if  ;; label = @1
+      unreachable
+    end
+    global.get 1
+    i64.const 14
+    i64.sub
+    global.set 1
+    i32.const 1
+    global.get 0
+    i32.const 6
+    i32.add
+    global.set 0
+    global.get 0
+    i32.const 1024
+    i32.gt_u
+    if  ;; label = @1
+      global.get 1
+      i64.const 1
+      i64.lt_u
+      if  ;; label = @2
+        unreachable
+      end
+      global.get 1
+      i64.const 1
+      i64.sub
+      global.set 1
+      unreachable
+    end
+    call 0
+    global.get 0
+    i32.const 6
+    i32.sub
+    global.set 0)
+  (func (;2;) (type 0) (param i32) (result i32)
+    global.get 1
+    i64.const 14
+    i64.lt_u
+    if  ;; label = @1
+      unreachable
+    end
+    global.get 1
+    i64.const 14
+    i64.sub
+    global.set 1
+    local.get 0
+    global.get 0
+    i32.const 6
+    i32.add
+    global.set 0
+    global.get 0
+    i32.const 1024
+    i32.gt_u
+    if  ;; label = @1
+      global.get 1
+      i64.const 1
+      i64.lt_u
+      if  ;; label = @2
+        unreachable
+      end
+      global.get 1
+      i64.const 1
+      i64.sub
+      global.set 1
+      unreachable
+    end
+    call 0
+    global.get 0
+    i32.const 6
+    i32.sub
+    global.set 0)
+  (func (;3;) (type 1) (result i32)
+    global.get 1
+    i64.const 13
+    i64.lt_u
+    if  ;; label = @1
+      unreachable
+    end
+    global.get 1
+    i64.const 13
+    i64.sub
+    global.set 1
+    global.get 0
+    i32.const 6
+    i32.add
+    global.set 0
+    global.get 0
+    i32.const 1024
+    i32.gt_u
+    if  ;; label = @1
+      global.get 1
+      i64.const 1
+      i64.lt_u
+      if  ;; label = @2
+        unreachable
+      end
+      global.get 1
+      i64.const 1
+      i64.sub
+      global.set 1
+      unreachable
+    end
+    call 1
+    global.get 0
+    i32.const 6
+    i32.sub
+    global.set 0)
+  (func (;4;) (type 0) (param i32) (result i32)
+    (local i64)
+    local.get 0
+    i64.extend_i32_u
+    i64.const 10000
+    i64.mul
+    local.tee 1
+    global.get 1
+    i64.gt_u
+    if  ;; label = @1
+      unreachable
+    end
+    global.get 1
+    local.get 1
+    i64.sub
+    global.set 1
+    local.get 0
+    memory.grow)
+  (memory (;0;) 0 1)
+  (global (;0;) (mut i32) (i32.const 0))
+  (global (;1;) (mut i64) (i64.const 0))
+  (export "grow" (func 2))
+  (export "call" (func 3))
+  (export "gas_left" (global 1)))
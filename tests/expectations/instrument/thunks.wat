@@ -0,0 +1,127 @@
+(module
+  (type (;0;) (func (param i32)))
+  (type (;1;) (func (param i32 i32) (result i32)))
+  (type (;2;) (func (param i32) (result i32)))
+  (type (;3;) (func))
+  (import "env" "foo" (func (;0;) (type 0)))
+  (import "env" "gas" (func (;1;) (type 0)))
+  (func (;2;) (type 1) (param i32 i32) (result i32)
+    i32.const 3
+    call 1
+    local.get 0
+    local.get 1
+    i32.add)
+  (func (;3;) (type 2) (param i32) (result i32)
+    i32.const 17
+    call 1
+    local.get 0
+    call 0
+    local.get 0
+    local.get 0
+    global.get 0
+    i32.const 3
+    i32.add
+    global.set 0
+    global.get 0
+    i32.const 1024
+    i32.gt_u
+    if  ;; label = @1
+      i32.const 1
+      call 1
+      unreachable
+    end
+    call 2
+    global.get 0
+    i32.const 3
+    i32.sub
+    global.set 0)
+  (func (;4;) (type 3)
+    i32.const 15
+    call 1
+    i32.const 1
+    global.get 0
+    i32.const 3
+    i32.add
+    global.set 0
+    global.get 0
+    i32.const 1024
+    i32.gt_u
+    if  ;; label = @1
+      i32.const 1
+      call 1
+      unreachable
+    end
+    call 3
+    global.get 0
+    i32.const 3
+    i32.sub
+    global.set 0
+    drop)
+  (func (;5;) (type 1) (param i32 i32) (result i32)
+    i32.const 15
+    call 1
+    local.get 0
+    local.get 1
+    global.get 0
+    i32.const 3
+    i32.add
+    global.set 0
+    global.get 0
+    i32.const 1024
+    i32.gt_u
+    if  ;; label = @1
+      i32.const 1
+      call 1
+      unreachable
+    end
+    call 2
+    global.get 0
+    i32.const 3
+    i32.sub
+    global.set 0)
+  (func (;6;) (type 2) (param i32) (result i32)
+    i32.const 14
+    call 1
+    local.get 0
+    global.get 0
+    i32.const 3
+    i32.add
+    global.set 0
+    global.get 0
+    i32.const 1024
+    i32.gt_u
+    if  ;; label = @1
+      i32.const 1
+      call 1
+      unreachable
+    end
+    call 3
+    global.get 0
+    i32.const 3
+    i32.sub
+    global.set 0)
+  (func (;7;) (type 3)
+    i32.const 13
+    call 1
+    global.get 0
+    i32.const 2
+    i32.add
+    global.set 0
+    global.get 0
+    i32.const 1024
+    i32.gt_u
+    if  ;; label = @1
+      i32.const 1
+      call 1
+      unreachable
+    end
+    call 4
+    global.get 0
+    i32.const 2
+    i32.sub
+    global.set 0)
+  (table (;0;) 2 funcref)
+  (global (;0;) (mut i32) (i32.const 0))
+  (export "add" (func 5))
+  (start 7)
+  (elem (;0;) (i32.const 0) func 5 6))
@@ -0,0 +1,78 @@
+(module
+  (type (;0;) (func (param i32 i32) (result i32)))
+  (type (;1;) (func (param i32) (result i32)))
+  (import "env" "foo" (func (;0;) (type 0)))
+  (func (;1;) (type 1) (param i32) (result i32)
+    (local i32)
+    i32.const 1
+    i32.const 2
+    local.get 0
+    local.tee 1
+    global.get 0
+    local.get 1
+    call 5
+    i32.add
+    global.set 0
+    global.get 0
+    i32.const 1024
+    i32.gt_u
+    if  ;; label = @1
+      unreachable
+    end
+    call_indirect (type 0)
+    global.get 0
+    local.get 1
+    call 5
+    i32.sub
+    global.set 0)
+  (func (;2;) (type 0) (param i32 i32) (result i32)
+    local.get 0
+    local.get 1
+    i32.add)
+  (func (;3;) (type 0) (param i32 i32) (result i32)
+    local.get 0
+    local.get 1
+    i32.sub)
+  (func (;4;) (type 0) (param i32 i32) (result i32)
+    local.get 0
+    local.get 1
+    local.get 1
+    i32.mul
+    i32.mul)
+  (func (;5;) (type 1) (param i32) (result i32)
+    block  ;; label = @1
+      block  ;; label = @2
+        block  ;; label = @3
+          local.get 0
+          br_table 0 (;@3;) 1 (;@2;) 1 (;@2;) 0 (;@3;) 2 (;@1;) 1 (;@2;) 0 (;@3;)
+        end
+        i32.const 0
+        return
+      end
+      i32.const 2
+      return
+    end
+    i32.const 3
+    return)
+  (func (;6;) (type 1) (param i32) (result i32)
+    local.get 0
+    global.get 0
+    i32.const 3
+    i32.add
+    global.set 0
+    global.get 0
+    i32.const 1024
+    i32.gt_u
+    if  ;; label = @1
+      unreachable
+    end
+    call 1
+    global.get 0
+    i32.const 3
+    i32.sub
+    global.set 0)
+  (table (;0;) 8 funcref)
+  (global (;0;) (mut i32) (i32.const 0))
+  (export "dispatch" (func 6))
+  (elem (;0;) (i32.const 0) func 0 2 3)
+  (elem (;1;) (i32.const 4) func 4 2))
@@ -0,0 +1,7 @@
+(module
+	(memory 1)
+
+	(func (export "grow") (param i32) (result i32)
+		(memory.grow (get_local 0))
+	)
+)
@@ -0,0 +1,8 @@
+(module
+	(memory 1)
+
+	;; Any request of more than 0 pages makes the charge exceed `i32::MAX`.
+	(func (export "grow_a_lot") (result i32)
+		(memory.grow (i32.const 65536))
+	)
+)
@@ -0,0 +1,11 @@
+(module
+  (func $grow (export "grow") (param i32) (result i32)
+    get_local 0
+    grow_memory
+  )
+  (func (export "call") (result i32)
+    i32.const 1
+    call $grow
+  )
+  (memory 0 1)
+)
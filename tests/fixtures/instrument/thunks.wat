@@ -0,0 +1,23 @@
+(module
+  (import "env" "foo" (func $foo (param i32)))
+  (func $add (export "add") (param i32 i32) (result i32)
+    get_local 0
+    get_local 1
+    i32.add
+  )
+  (func $caller (param i32) (result i32)
+    get_local 0
+    call $foo
+    get_local 0
+    get_local 0
+    call $add
+  )
+  (func $start
+    i32.const 1
+    call $caller
+    drop
+  )
+  (table 2 anyfunc)
+  (elem (i32.const 0) $add $caller)
+  (start $start)
+)
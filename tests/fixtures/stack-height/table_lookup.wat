@@ -0,0 +1,32 @@
+(module
+  (type $binary (func (param i32 i32) (result i32)))
+  (import "env" "foo" (func $foo (param i32 i32) (result i32)))
+  (func $dispatch (export "dispatch") (param i32) (result i32)
+    i32.const 1
+    i32.const 2
+    get_local 0
+    call_indirect (type $binary)
+  )
+  (func $i32.add (param i32 i32) (result i32)
+    get_local 0
+    get_local 1
+    i32.add
+  )
+  (func $i32.sub (param i32 i32) (result i32)
+    get_local 0
+    get_local 1
+    i32.sub
+  )
+  (func $i32.mul3 (param i32 i32) (result i32)
+    get_local 0
+    get_local 1
+    get_local 1
+    i32.mul
+    i32.mul
+  )
+  (table 8 anyfunc)
+
+  ;; Entries with equal stack costs share a block of the lookup function.
+  (elem (i32.const 0) $foo $i32.add $i32.sub)
+  (elem (i32.const 4) $i32.mul3 $i32.add)
+)